@@ -0,0 +1,248 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use crate::{
+    config::{CapacityConfig, HealthConfig},
+    health,
+    metrics::Percentile,
+    native::{self, RequestTiming},
+    paths::TestPath,
+    runtime::{self, Runtimes},
+    targets::TestTarget,
+    writes,
+};
+
+/// The rate (requests per second) that the search starts at.
+const INITIAL_RATE: f64 = 100.0;
+/// The search gives up on a path once it fails at this rate or lower.
+const MIN_RATE: f64 = 1.0;
+/// The search stops once the passing and failing rates are within this fraction of each other.
+const RESOLUTION: f64 = 0.05;
+const MAX_PROBES: usize = 20;
+const PROBE_DURATION: Duration = Duration::from_secs(5);
+/// How long to let the server recover between probes.
+const PROBE_COOL_DOWN: Duration = Duration::from_secs(2);
+
+/// The service level objective that a target must meet to sustain a given rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Slo {
+    pub p99_latency: Duration,
+    /// The maximum fraction of requests (from 0 to 1) that may fail.
+    pub max_error_rate: f64,
+}
+
+impl From<&CapacityConfig> for Slo {
+    fn from(config: &CapacityConfig) -> Self {
+        Self {
+            p99_latency: Duration::from_millis(config.slo_p99_ms),
+            max_error_rate: config.slo_max_error_rate,
+        }
+    }
+}
+
+impl Slo {
+    fn is_met_by(&self, probe: &CapacityProbe) -> bool {
+        probe.p99_latency <= self.p99_latency && probe.error_rate <= self.max_error_rate
+    }
+}
+
+/// The outcome of sending requests at a single constant rate.
+#[derive(Debug, Clone)]
+pub struct CapacityProbe {
+    /// The offered request rate, per second.
+    pub rate: f64,
+    /// The rate at which successful responses were received, per second, over the time until the
+    /// last response.
+    pub achieved_qps: f64,
+    /// The p99 latency of successful requests, so that failures that time out or fail fast don't
+    /// skew it.
+    pub p99_latency: Duration,
+    pub error_rate: f64,
+}
+
+impl CapacityProbe {
+    fn from_timings(rate: f64, timings: &[RequestTiming]) -> Self {
+        let latencies: Vec<Duration> = timings
+            .iter()
+            .filter(|t| t.is_success())
+            .map(|t| t.latency)
+            .collect();
+        let num_successes = latencies.len();
+        let run_secs = native::run_duration(timings).as_secs_f64();
+        Self {
+            rate,
+            achieved_qps: if run_secs > 0.0 {
+                num_successes as f64 / run_secs
+            } else {
+                0.0
+            },
+            p99_latency: Percentile::from_latencies(&latencies, &[99.0])
+                .first()
+                .map(|p| p.latency)
+                .unwrap_or_default(),
+            error_rate: match timings.len() {
+                0 => 1.0,
+                n => 1.0 - num_successes as f64 / n as f64,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CapacityResult<'a, 'b> {
+    pub target: TestTarget<'b>,
+    pub path: &'a TestPath,
+    pub slo: Slo,
+    /// The highest rate at which the SLO was met, if any.
+    pub max_sustainable: Option<CapacityProbe>,
+    pub num_probes: usize,
+}
+
+/// Chooses the next rate to probe, given the highest passing and lowest failing rates so far.
+///
+/// Doubles the rate until the SLO fails, then bisects. Returns `None` once the search is done.
+fn next_rate(passing: Option<f64>, failing: Option<f64>) -> Option<f64> {
+    match (passing, failing) {
+        (Some(passing), Some(failing)) if failing - passing <= passing * RESOLUTION => None,
+        (Some(passing), Some(failing)) => Some((passing + failing) / 2.0),
+        (Some(passing), None) => Some(passing * 2.0),
+        (None, Some(failing)) if failing <= MIN_RATE => None,
+        (None, Some(failing)) => Some(failing / 2.0),
+        (None, None) => Some(INITIAL_RATE),
+    }
+}
+
+async fn probe_rate(
+    target: &TestTarget<'_>,
+    full_path: &str,
+    rate: f64,
+) -> Result<CapacityProbe, Box<dyn Error>> {
+    // Keeps a second's worth of requests' connections open, so that they're reused rather than
+    // reconnected while the server keeps up.
+    let client = native::client_for(target, rate.ceil() as usize)?;
+    let timings = native::run_open_loop(&client, full_path, rate, PROBE_DURATION).await;
+    let probe = CapacityProbe::from_timings(rate, &timings);
+    println!(
+        "\tProbed {:.1} req/s: achieved {:.1} req/s, p99 {:?}, error rate {:.4}",
+        probe.rate, probe.achieved_qps, probe.p99_latency, probe.error_rate
+    );
+    Ok(probe)
+}
+
+async fn bench_path<'a: 'c, 'b, 'c>(
+    target: TestTarget<'b>,
+    path: &'a TestPath,
+    slo: Slo,
+) -> Result<CapacityResult<'a, 'b>, Box<dyn Error>> {
+    let full_path = target.url(&path.path);
+    let client = native::client_for(&target, 1)?;
+    native::run_closed_loop(&client, &full_path, 1, Duration::from_secs(1)).await;
+
+    let mut max_sustainable: Option<CapacityProbe> = None;
+    let mut failing_rate = None;
+    let mut num_probes = 0;
+    while num_probes < MAX_PROBES {
+        let rate = match next_rate(max_sustainable.as_ref().map(|p| p.rate), failing_rate) {
+            Some(rate) => rate,
+            None => break,
+        };
+        let probe = probe_rate(&target, &full_path, rate).await?;
+        num_probes += 1;
+        if slo.is_met_by(&probe) {
+            max_sustainable = Some(probe);
+        } else {
+            failing_rate = Some(rate);
+        }
+        tokio::time::sleep(PROBE_COOL_DOWN).await;
+    }
+
+    Ok(CapacityResult {
+        target,
+        path,
+        slo,
+        max_sustainable,
+        num_probes,
+    })
+}
+
+/// Finds the maximum rate each target can sustain on each path without violating the SLO,
+/// writing results to a CSV in out_dir.
+pub async fn benchmark_all<'a>(
+    targets: &Vec<TestTarget<'a>>,
+    out_dir: PathBuf,
+    config: &CapacityConfig,
+    health_config: &HealthConfig,
+    runtimes: &Runtimes,
+) -> Result<(), Box<dyn Error>> {
+    let slo = Slo::from(config);
+    let mut capacity_csv_path = out_dir.clone();
+    capacity_csv_path.push("capacity.csv");
+    let mut capacity_csv = csv::Writer::from_path(&capacity_csv_path)?;
+
+    for target in targets {
+        let target = &target.with_host_port()?;
+        let log_path = runtime::prep_log_path(&out_dir, target)?;
+        let Some(container) =
+            health::start_healthy(runtimes, target, &log_path, health_config).await?
+        else {
+            continue;
+        };
+
+        println!("Starting capacity search on target {}", target.name());
+        for path in config.paths.iter() {
+            println!("Searching capacity of path {:?}", path);
+            let result = bench_path(target.clone(), path, slo).await?;
+            println!(
+                "\tMax sustainable rate: {:?}",
+                result.max_sustainable.as_ref().map(|p| p.rate)
+            );
+            writes::write_capacity_result(&mut capacity_csv, &result)?;
+        }
+        println!("Finished capacity search on target {}", target.name());
+
+        container.stop().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::Outcome;
+
+    #[test]
+    fn test_next_rate() {
+        assert_eq!(next_rate(None, None), Some(INITIAL_RATE));
+        assert_eq!(next_rate(Some(100.0), None), Some(200.0));
+        assert_eq!(next_rate(None, Some(100.0)), Some(50.0));
+        assert_eq!(next_rate(None, Some(MIN_RATE)), None);
+        assert_eq!(next_rate(Some(100.0), Some(200.0)), Some(150.0));
+        assert_eq!(next_rate(Some(100.0), Some(104.0)), None);
+    }
+
+    #[test]
+    fn test_probe_from_timings() {
+        let timing = |i: u64, latency: Duration, outcome| RequestTiming {
+            sent_at: Duration::from_millis(i * 10),
+            latency,
+            outcome,
+        };
+        // 95 fast successes, and 5 requests that timed out.
+        let timings: Vec<RequestTiming> = (0..100)
+            .map(|i| match i % 20 {
+                0 => timing(i, Duration::from_secs(10), Outcome::Timeout),
+                _ => timing(i, Duration::from_millis(10), Outcome::Response(200)),
+            })
+            .collect();
+        let probe = CapacityProbe::from_timings(100.0, &timings);
+        assert_eq!(probe.p99_latency, Duration::from_millis(10));
+        assert_relative_eq!(probe.error_rate, 0.05);
+
+        let slo = |max_error_rate| Slo {
+            p99_latency: Duration::from_millis(100),
+            max_error_rate,
+        };
+        assert!(slo(0.1).is_met_by(&probe));
+        assert!(!slo(0.01).is_met_by(&probe));
+    }
+}
@@ -0,0 +1,662 @@
+use std::{error::Error, fs, path::Path};
+
+use crate::{
+    cpuset::CpuSet, health::UnhealthyPolicy, paths::TestPath, perf::Engine, runtime::RuntimeKind,
+    targets::Compression,
+};
+
+/// Benchmark configuration, as loaded from a TOML file.
+///
+/// Anything not specified in the file falls back to the built-in defaults.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub targets: Vec<TargetConfig>,
+    /// The compression settings to test each target with.
+    pub compression: Vec<Compression>,
+    pub resources: ResourceConfig,
+    pub health: HealthConfig,
+    pub noise: NoiseConfig,
+    pub perf: PerfConfig,
+    pub capacity: CapacityConfig,
+    pub load: LoadConfig,
+    pub warm_up: WarmUpConfig,
+}
+
+impl Config {
+    /// Reads a config file. It isn't validated yet, as command line options may still override it.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Checks for settings that are invalid in combination.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        for (i, target) in self.targets.iter().enumerate() {
+            if self.targets[..i]
+                .iter()
+                .any(|other| other.server_name == target.server_name)
+            {
+                return Err(
+                    format!("Target {} is listed more than once", target.server_name).into(),
+                );
+            }
+            let is_process = target.runtime == RuntimeKind::Process;
+            if is_process == target.command.is_empty() {
+                return Err(format!(
+                    "Target {} must specify a command if and only if its runtime is \"process\"",
+                    target.server_name
+                )
+                .into());
+            }
+            if target.runtime == RuntimeKind::Remote && target.base_url.is_none() {
+                return Err(format!(
+                    "Remote target {} must specify a base_url",
+                    target.server_name
+                )
+                .into());
+            }
+            if target.base_url.is_some() && target.host_port == 0 {
+                return Err(format!(
+                    "Target {} can't pick a free host port if it sets a base_url",
+                    target.server_name
+                )
+                .into());
+            }
+        }
+        let counts = [
+            ("perf.duration_secs", self.perf.duration_secs),
+            ("perf.connections", self.perf.connections as u64),
+            ("load.users", self.load.users as u64),
+            ("load.iterations", self.load.iterations as u64),
+            ("warm_up.iterations", self.warm_up.iterations as u64),
+            ("capacity.slo_p99_ms", self.capacity.slo_p99_ms),
+        ];
+        if let Some((name, _)) = counts.iter().find(|(_, count)| *count == 0) {
+            return Err(format!("{} must be at least 1", name).into());
+        }
+        for (name, values) in [
+            ("resources.num_cpus", &self.resources.num_cpus),
+            ("resources.ram_mb", &self.resources.ram_mb),
+        ] {
+            if values.contains(&0) {
+                return Err(format!("Every value of {} must be at least 1", name).into());
+            }
+        }
+        let runs_wrk = self.perf.engine == Engine::Wrk || self.perf.wrk2.is_some();
+        if runs_wrk && (self.perf.threads == 0 || self.perf.threads > self.perf.connections) {
+            return Err(format!(
+                "perf.threads must be between 1 and perf.connections ({}), but was {}",
+                self.perf.connections, self.perf.threads
+            )
+            .into());
+        }
+        if let Some(rate) = self
+            .perf
+            .rate
+            .filter(|rate| !(rate.is_finite() && *rate > 0.0))
+        {
+            return Err(format!("perf.rate must be a positive number, but was {}", rate).into());
+        }
+        if !(0.0..=1.0).contains(&self.capacity.slo_max_error_rate) {
+            return Err(format!(
+                "capacity.slo_max_error_rate must be between 0 and 1, but was {}",
+                self.capacity.slo_max_error_rate
+            )
+            .into());
+        }
+        if let (Some(server), Some(client)) =
+            (&self.resources.server_cpuset, &self.resources.client_cpuset)
+        {
+            if server.intersects(client) {
+                return Err(format!(
+                    "The server cpuset {} and client cpuset {} must not overlap",
+                    server, client
+                )
+                .into());
+            }
+        }
+        if self.resources.server_cpuset.is_some() || self.resources.client_cpuset.is_some() {
+            let available = CpuSet::available_to_server(
+                self.resources.server_cpuset.as_ref(),
+                self.resources.client_cpuset.as_ref(),
+            );
+            if let Some(num_cpus) = self
+                .resources
+                .num_cpus
+                .iter()
+                .find(|&&num_cpus| num_cpus > available.cpus().len())
+            {
+                return Err(format!(
+                    "num_cpus {} is more than the {} CPUs ({}) available to servers",
+                    num_cpus,
+                    available.cpus().len(),
+                    available
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            targets: vec![],
+            compression: vec![Compression::None],
+            resources: ResourceConfig::default(),
+            health: HealthConfig::default(),
+            noise: NoiseConfig::default(),
+            perf: PerfConfig::default(),
+            capacity: CapacityConfig::default(),
+            load: LoadConfig::default(),
+            warm_up: WarmUpConfig::default(),
+        }
+    }
+}
+
+/// Describes a server to benchmark.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    /// A name for the server under test, in the form "lang-framework". For container runtimes,
+    /// this must match an image tagged "hello-lang-framework".
+    pub server_name: String,
+    #[serde(default)]
+    pub runtime: RuntimeKind,
+    /// The program and arguments that start the server, when run as a local process.
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub health: HealthCheck,
+    /// The port the server listens on inside its container.
+    #[serde(default = "default_port")]
+    pub container_port: u16,
+    /// The port on the host that the container port is published to, or 0 to pick a free port
+    /// when the server is started. Local processes are told to listen on this port.
+    #[serde(default = "default_port")]
+    pub host_port: u16,
+    /// The URL to send requests to, if not "http://localhost:<host_port>".
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+impl TargetConfig {
+    /// Describes a server run in docker, with the default health check.
+    pub fn new(server_name: String) -> Self {
+        Self {
+            server_name,
+            runtime: RuntimeKind::default(),
+            command: vec![],
+            health: HealthCheck::default(),
+            container_port: default_port(),
+            host_port: default_port(),
+            base_url: None,
+        }
+    }
+}
+
+/// The request that shows a server is ready, and the response it must give.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    pub path: String,
+    /// The expected response status.
+    pub status: u16,
+    /// If set, the response body must contain this.
+    pub body_contains: Option<String>,
+}
+
+impl HealthCheck {
+    /// Checks whether a response shows that the server is ready.
+    pub fn matches(&self, status: u16, body: &str) -> bool {
+        status == self.status
+            && self
+                .body_contains
+                .as_ref()
+                .is_none_or(|expected| body.contains(expected.as_str()))
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: String::from("/strings/hello"),
+            status: 200,
+            body_contains: None,
+        }
+    }
+}
+
+/// The resources each server is run with. Every target is benchmarked with every combination.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceConfig {
+    pub num_cpus: Vec<usize>,
+    pub ram_mb: Vec<usize>,
+    /// The CPUs to pin each server to, e.g. "0-3". Each server is pinned to the first `num_cpus`
+    /// of them, so every `num_cpus` must fit.
+    pub server_cpuset: Option<CpuSet>,
+    /// The CPUs to pin the load generator to. Must not overlap the server cpuset.
+    pub client_cpuset: Option<CpuSet>,
+}
+
+impl Default for ResourceConfig {
+    fn default() -> Self {
+        Self {
+            num_cpus: vec![1],
+            ram_mb: vec![128],
+            server_cpuset: None,
+            client_cpuset: None,
+        }
+    }
+}
+
+/// How to wait for each server to become healthy after starting it.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// How long a server may take to become healthy.
+    pub startup_timeout_secs: u64,
+    /// The delay between the first health checks.
+    pub poll_interval_ms: u64,
+    /// The delay doubles after each check, up to this.
+    pub max_poll_interval_ms: u64,
+    pub on_failure: UnhealthyPolicy,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            startup_timeout_secs: 60,
+            poll_interval_ms: 50,
+            max_poll_interval_ms: 1000,
+            on_failure: UnhealthyPolicy::Fail,
+        }
+    }
+}
+
+/// When to consider the host too busy for perf and load benchmarks to be trusted.
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoiseConfig {
+    /// The share of the host's CPU time, as a percentage, that other processes may use while the
+    /// server is idle before each run. Also applies during the run to the CPUs outside the server
+    /// and client cpusets, when both are set.
+    pub max_background_cpu_percent: f64,
+    /// The share of CPU time, as a percentage, that the hypervisor may steal before or during a
+    /// run.
+    pub max_steal_percent: f64,
+    /// The highest 1 minute load average per CPU that the host may reach during a run. The
+    /// benchmark's own server and client count towards it.
+    pub max_load_avg_per_cpu: f64,
+    /// How far, as a percentage, the CPU frequency may drop below its highest value during a run,
+    /// e.g. from thermal throttling.
+    pub max_cpu_mhz_drop_percent: f64,
+    /// If set, stops the benchmark when the host is too busy, rather than flagging the results.
+    pub strict: bool,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self {
+            max_background_cpu_percent: 10.0,
+            max_steal_percent: 5.0,
+            max_load_avg_per_cpu: 2.0,
+            max_cpu_mhz_drop_percent: 10.0,
+            strict: false,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PerfConfig {
+    pub engine: Engine,
+    /// How long to send requests to each path before measuring it.
+    pub warm_up_secs: u64,
+    /// How long to measure each path for.
+    pub duration_secs: u64,
+    /// The number of threads wrk and wrk2 send requests from. Ignored by the native engine.
+    pub threads: usize,
+    /// The number of concurrent connections to send requests over, shared between the threads.
+    pub connections: usize,
+    /// If set, each path is also benchmarked at this constant rate (requests per second).
+    pub rate: Option<f64>,
+    /// The wrk2 program to run the constant rate benchmarks with, e.g. "wrk2", rather than the
+    /// native engine. It is run with `-U`, so that it also prints latency uncorrected for
+    /// coordinated omission, but only the corrected latency is recorded.
+    pub wrk2: Option<String>,
+    pub paths: Vec<TestPath>,
+}
+
+impl Default for PerfConfig {
+    fn default() -> Self {
+        Self {
+            engine: Engine::Wrk,
+            warm_up_secs: 1,
+            duration_secs: 10,
+            threads: 1,
+            connections: 1,
+            rate: None,
+            wrk2: None,
+            paths: default_perf_paths(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityConfig {
+    /// The p99 latency, in ms, that a target must stay within.
+    pub slo_p99_ms: u64,
+    /// The fraction of requests (from 0 to 1) that may fail.
+    pub slo_max_error_rate: f64,
+    pub paths: Vec<TestPath>,
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        Self {
+            slo_p99_ms: 100,
+            slo_max_error_rate: 0.01,
+            paths: default_perf_paths(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadConfig {
+    /// The number of concurrent users.
+    pub users: usize,
+    /// How long to spend launching all users.
+    pub startup_time_secs: u64,
+    /// How long to run each load test for, once all users are launched.
+    pub run_time_secs: u64,
+    /// The number of load tests to run against each target.
+    pub iterations: usize,
+    pub transactions: Vec<LoadTransaction>,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            users: 6,
+            startup_time_secs: 60,
+            run_time_secs: 10,
+            iterations: 3,
+            transactions: vec![
+                LoadTransaction {
+                    name: String::from("strings"),
+                    paths: vec![
+                        TestPath::new("/strings/hello", "hello"),
+                        TestPath::new("/strings/hello?name=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "hello-long"),
+                        TestPath::new("/strings/async-hello", "async-hello"),
+                        TestPath::new("/strings/lines?n=10000", "lines"),
+                    ],
+                },
+                LoadTransaction {
+                    name: String::from("static"),
+                    paths: vec![
+                        TestPath::new("/static/basic.html", "basic-html"),
+                        TestPath::new("/static/scout.webp", "scout-img"),
+                    ],
+                },
+                LoadTransaction {
+                    name: String::from("math"),
+                    paths: vec![
+                        TestPath::new("/math/power-reciprocals-alt?n=1000", "power-sum-easy"),
+                        TestPath::new("/math/power-reciprocals-alt?n=10000000", "power-sum-hard"),
+                    ],
+                },
+            ],
+        }
+    }
+}
+
+/// A named group of paths that each load test user requests in sequence.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadTransaction {
+    pub name: String,
+    pub paths: Vec<TestPath>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmUpConfig {
+    /// The number of times to start each server for each path.
+    pub iterations: usize,
+    /// The number of requests to time after the first one, which is sent as soon as the server
+    /// passes its health check.
+    pub requests: usize,
+    pub paths: Vec<TestPath>,
+}
+
+impl Default for WarmUpConfig {
+    fn default() -> Self {
+        Self {
+            iterations: 3,
+            requests: 5,
+            paths: vec![
+                TestPath::new("/strings/hello", "hello"),
+                TestPath::new("/strings/lines?n=50000", "lines"),
+                TestPath::new("/static/basic.html", "static-text"),
+                TestPath::new("/math/power-reciprocals-alt?n=1000000", "powers-sum"),
+            ],
+        }
+    }
+}
+
+fn default_perf_paths() -> Vec<TestPath> {
+    vec![
+        TestPath::new("/strings/hello", "hello"),
+        TestPath::new("/strings/hello?name=fluffy%20dog", "hello-param"),
+        TestPath::new("/strings/hello?name=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "hello-long"),
+        TestPath::new("/strings/async-hello", "async-hello"),
+        TestPath::new("/strings/lines?n=50000", "lines"),
+        TestPath::new("/static/scout.webp", "static-img"),
+        TestPath::new("/static/basic.html", "static-text"),
+        TestPath::new("/math/power-reciprocals-alt?n=10000", "math-powers-light"),
+        TestPath::new("/math/power-reciprocals-alt?n=10000000", "math-powers-heavy"),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partial_config_uses_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [[targets]]
+            server_name = "rust-axum"
+
+            [perf]
+            engine = "native"
+            paths = [{ path = "/strings/hello", name = "hello" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.targets[0].server_name, "rust-axum");
+        assert_eq!(config.perf.engine, Engine::Native);
+        assert_eq!(
+            config.perf.paths,
+            vec![TestPath::new("/strings/hello", "hello")]
+        );
+        assert_eq!(config.perf.duration_secs, 10);
+        assert_eq!(config.resources.ram_mb, vec![128]);
+        assert_eq!(config.warm_up.paths.len(), 4);
+    }
+
+    #[test]
+    fn test_validates_process_commands() {
+        let config: Config = toml::from_str(
+            r#"
+            [[targets]]
+            server_name = "rust-axum"
+            runtime = "process"
+            command = ["./target/release/rust-axum"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: Config = toml::from_str(
+            r#"
+            [[targets]]
+            server_name = "rust-axum"
+            runtime = "process"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validates_cpusets() {
+        let config: Config = toml::from_str(
+            r#"
+            [resources]
+            server_cpuset = "0-3"
+            client_cpuset = "4,5"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.resources.server_cpuset.unwrap().cpus(),
+            &[0, 1, 2, 3]
+        );
+
+        let config: Config = toml::from_str(
+            r#"
+            [resources]
+            server_cpuset = "0-3"
+            client_cpuset = "3-5"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            [resources]
+            num_cpus = [2, 4, 6]
+            server_cpuset = "0-3"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        assert!(toml::from_str::<Config>("resources.server_cpuset = \"3-\"").is_err());
+    }
+
+    #[test]
+    fn test_validates_perf_threads() {
+        let config: Config = toml::from_str("perf = { threads = 4, connections = 8 }").unwrap();
+        assert!(config.validate().is_ok());
+        let config: Config = toml::from_str("perf = { threads = 4, connections = 2 }").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("perf = { threads = 0 }").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config =
+            toml::from_str("perf = { engine = \"native\", threads = 4, connections = 2 }").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validates_counts() {
+        for setting in [
+            "perf.duration_secs",
+            "perf.connections",
+            "load.users",
+            "load.iterations",
+            "warm_up.iterations",
+            "capacity.slo_p99_ms",
+        ] {
+            let config: Config = toml::from_str(&format!("{} = 0", setting)).unwrap();
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_validates_unique_server_names() {
+        let config: Config = toml::from_str(
+            r#"
+            [[targets]]
+            server_name = "rust-axum"
+
+            [[targets]]
+            server_name = "rust-axum"
+            runtime = "podman"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validates_resources() {
+        let config: Config =
+            toml::from_str("resources = { num_cpus = [1, 2], ram_mb = [64] }").unwrap();
+        assert!(config.validate().is_ok());
+        let config: Config = toml::from_str("resources.num_cpus = [0, 2]").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("resources.ram_mb = [0]").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validates_perf_rate() {
+        let config: Config = toml::from_str("perf.rate = 0.5").unwrap();
+        assert!(config.validate().is_ok());
+        for rate in ["0.0", "-10.0", "nan", "inf"] {
+            let config: Config = toml::from_str(&format!("perf.rate = {}", rate)).unwrap();
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_validates_slo_max_error_rate() {
+        let config: Config = toml::from_str("capacity.slo_max_error_rate = 0.0").unwrap();
+        assert!(config.validate().is_ok());
+        for rate in ["-0.1", "1.5", "nan"] {
+            let config: Config =
+                toml::from_str(&format!("capacity.slo_max_error_rate = {}", rate)).unwrap();
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_health_check_matches() {
+        let check = HealthCheck {
+            path: String::from("/ready"),
+            status: 204,
+            body_contains: None,
+        };
+        assert!(check.matches(204, ""));
+        assert!(!check.matches(200, ""));
+
+        let check = HealthCheck {
+            body_contains: Some(String::from("\"ready\":true")),
+            ..HealthCheck::default()
+        };
+        assert!(check.matches(200, "{\"ready\":true}"));
+        assert!(!check.matches(200, "{\"ready\":false}"));
+    }
+
+    #[test]
+    fn test_rejects_unknown_fields() {
+        assert!(toml::from_str::<Config>("[perf]\nduration = 10").is_err());
+    }
+}
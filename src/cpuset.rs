@@ -0,0 +1,200 @@
+use std::{fmt, fs, io, str::FromStr};
+
+/// A set of CPUs, written like Linux cpusets and `docker run --cpuset-cpus`, e.g. "0-3,6".
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CpuSet {
+    /// The CPU numbers, sorted and without duplicates.
+    cpus: Vec<usize>,
+}
+
+impl CpuSet {
+    pub fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+
+    pub fn intersects(&self, other: &CpuSet) -> bool {
+        self.cpus.iter().any(|cpu| other.cpus.contains(cpu))
+    }
+
+    /// Gets the CPUs in this set that aren't in `other`.
+    pub fn difference(&self, other: &CpuSet) -> CpuSet {
+        let cpus = self
+            .cpus
+            .iter()
+            .copied()
+            .filter(|cpu| !other.cpus.contains(cpu))
+            .collect();
+        CpuSet { cpus }
+    }
+
+    /// Gets the `n` lowest-numbered CPUs in this set, or all of them if it has fewer.
+    pub fn first(&self, n: usize) -> CpuSet {
+        CpuSet {
+            cpus: self.cpus.iter().copied().take(n).collect(),
+        }
+    }
+
+    /// Gets the host's online CPUs.
+    pub fn online() -> CpuSet {
+        fs::read_to_string("/sys/devices/system/cpu/online")
+            .ok()
+            .and_then(|online| online.trim().parse().ok())
+            .unwrap_or_else(|| CpuSet {
+                cpus: (0..online_cpus()).collect(),
+            })
+    }
+
+    /// Gets the CPUs that servers may run on: the server cpuset, or if there is none, the host's
+    /// CPUs outside the client cpuset, so that servers don't compete with the load generator.
+    pub fn available_to_server(server: Option<&CpuSet>, client: Option<&CpuSet>) -> CpuSet {
+        match (server, client) {
+            (Some(server), _) => server.clone(),
+            (None, Some(client)) => Self::online().difference(client),
+            (None, None) => Self::online(),
+        }
+    }
+
+    /// Pins every thread of the current process to this set.
+    ///
+    /// Threads and child processes started afterwards inherit the affinity of the thread that
+    /// starts them, so they stay within the set too.
+    pub fn pin_current_process(&self) -> io::Result<()> {
+        for task in fs::read_dir("/proc/self/task")? {
+            let Some(tid) = task?.file_name().to_str().and_then(|t| t.parse().ok()) else {
+                continue;
+            };
+            match set_affinity(tid, &self.cpus) {
+                // The thread may have exited since listing them.
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gets the number of online CPUs, which is at least 1.
+pub fn online_cpus() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    let num_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    num_cpus.max(1) as usize
+}
+
+/// Sets the CPU affinity of a thread, or of the calling thread if `tid` is 0.
+///
+/// Only calls async-signal-safe functions, so that it can run between fork and exec.
+pub fn set_affinity(tid: libc::pid_t, cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is plain data, and the libc calls only read from or write to the values
+    // passed to them.
+    unsafe {
+        let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut cpu_set);
+        }
+        if libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl FromStr for CpuSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cpuset {:?}; expected e.g. \"0-3,6\"", s);
+        let parse_cpu = |cpu: &str| -> Result<usize, String> {
+            match cpu.trim().parse() {
+                Ok(cpu) if cpu < libc::CPU_SETSIZE as usize => Ok(cpu),
+                _ => Err(invalid()),
+            }
+        };
+        let mut cpus = vec![];
+        for range in s.split(',') {
+            match range.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse_cpu(first)?, parse_cpu(last)?);
+                    if first > last {
+                        return Err(invalid());
+                    }
+                    cpus.extend(first..=last);
+                }
+                None => cpus.push(parse_cpu(range)?),
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuSet { cpus })
+    }
+}
+
+impl TryFrom<String> for CpuSet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CpuSet> for String {
+    fn from(cpuset: CpuSet) -> Self {
+        cpuset.to_string()
+    }
+}
+
+impl fmt::Display for CpuSet {
+    /// Writes the set in its shortest form, joining consecutive CPUs into ranges.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for &cpu in self.cpus.iter() {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == cpu => *last = cpu,
+                _ => ranges.push((cpu, cpu)),
+            }
+        }
+        let ranges: Vec<String> = ranges
+            .into_iter()
+            .map(|(first, last)| match first == last {
+                true => first.to_string(),
+                false => format!("{}-{}", first, last),
+            })
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpuset() {
+        let cpuset: CpuSet = "4,0-2, 6 ,2".parse().unwrap();
+        assert_eq!(cpuset.cpus(), &[0, 1, 2, 4, 6]);
+        assert_eq!(cpuset.to_string(), "0-2,4,6");
+        assert_eq!("3".parse::<CpuSet>().unwrap().to_string(), "3");
+
+        assert!("".parse::<CpuSet>().is_err());
+        assert!("2-1".parse::<CpuSet>().is_err());
+        assert!("0-".parse::<CpuSet>().is_err());
+        assert!("a".parse::<CpuSet>().is_err());
+        assert!("100000".parse::<CpuSet>().is_err());
+    }
+
+    #[test]
+    fn test_intersects() {
+        let server: CpuSet = "0-3".parse().unwrap();
+        assert!(!server.intersects(&"4-7".parse().unwrap()));
+        assert!(server.intersects(&"3-4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_difference_and_first() {
+        let host: CpuSet = "0-7".parse().unwrap();
+        let free = host.difference(&"0,2-3".parse().unwrap());
+        assert_eq!(free.cpus(), &[1, 4, 5, 6, 7]);
+        assert_eq!(free.first(2).cpus(), &[1, 4]);
+        assert_eq!(free.first(8), free);
+    }
+}
@@ -1,76 +1,816 @@
-use std::{io::Error, process::Command};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
 
-use crate::targets::TestTarget;
+use async_trait::async_trait;
+use hyper::{body, client::conn, Body, Method, Request, StatusCode};
+use regex::Regex;
+use tokio::net::UnixStream;
 
-pub fn start_container(target: &TestTarget) -> Result<String, Error> {
-    let memory_arg = format!("{}m", target.ram_mb);
-    let cpus_str = target.num_cpus.to_string();
-    let name = target.name();
-    let docker_target = target.docker_target();
+use crate::{
+    runtime::{ContainerRuntime, ContainerState, ContainerStats, ImageInfo, RuntimeError},
+    targets::TestTarget,
+};
 
-    delete_container_if_present(&name)?;
+/// The Docker Engine API version to request.
+const API_VERSION: &str = "v1.41";
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+const DEFAULT_PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+/// Labels every container started by this tool, so that stale ones can be found.
+const BENCH_LABEL: &str = "hello-world-bench";
 
-    println!("Starting container {} with image {}", &name, &docker_target);
-    Command::new("docker")
-        .args([
-            "run",
-            "-m",
-            &memory_arg,
-            "--memory-swap",
-            &memory_arg,
-            "--cpus",
-            &cpus_str,
-            "-p",
-            "8080:8080",
-            "--name",
-            &name,
-            &docker_target,
-        ])
-        .spawn()?;
+/// Runs containers with the Docker Engine API, over its unix socket. Also works with Podman's
+/// Docker-compatible API.
+#[derive(Debug, Clone)]
+pub struct DockerRuntime {
+    socket: PathBuf,
+}
+
+impl DockerRuntime {
+    pub fn new(socket: PathBuf) -> Self {
+        Self { socket }
+    }
+
+    /// Connects to the socket given by `DOCKER_HOST` if it's a unix socket, or else the default.
+    pub fn from_env() -> Self {
+        let socket = std::env::var("DOCKER_HOST")
+            .ok()
+            .and_then(|host| host.strip_prefix("unix://").map(String::from))
+            .unwrap_or_else(|| String::from(DEFAULT_SOCKET));
+        Self::new(PathBuf::from(socket))
+    }
+
+    /// Connects to Podman's Docker-compatible API.
+    ///
+    /// Uses the socket given by `CONTAINER_HOST` if it's a unix socket, or else the rootless socket
+    /// for the current user, falling back to the rootful socket.
+    pub fn podman_from_env() -> Self {
+        let socket = std::env::var("CONTAINER_HOST")
+            .ok()
+            .and_then(|host| host.strip_prefix("unix://").map(PathBuf::from))
+            .or_else(|| {
+                std::env::var("XDG_RUNTIME_DIR")
+                    .ok()
+                    .map(|dir| PathBuf::from(dir).join("podman/podman.sock"))
+                    .filter(|socket| socket.exists())
+            })
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_PODMAN_SOCKET));
+        Self::new(socket)
+    }
+
+    /// Sends a request to the Docker API, returning the response status and body.
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<(StatusCode, Vec<u8>), RuntimeError> {
+        let body = match body {
+            Some(body) => Body::from(serde_json::to_vec(&body)?),
+            None => Body::empty(),
+        };
+        self.send(method, path, "application/json", body).await
+    }
+
+    /// Sends a request with a body of the given content type to the Docker API, returning the
+    /// response status and body.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: &str,
+        body: Body,
+    ) -> Result<(StatusCode, Vec<u8>), RuntimeError> {
+        let stream = UnixStream::connect(&self.socket).await?;
+        let (mut sender, connection) = conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("/{}{}", API_VERSION, path))
+            .header("Host", "docker")
+            .header("Content-Type", content_type)
+            .body(body)
+            .map_err(|e| RuntimeError::Connection(e.into()))?;
+
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await?;
+        Ok((status, bytes.to_vec()))
+    }
+
+    async fn delete_container_if_present(&self, name: &str) -> Result<(), RuntimeError> {
+        println!("Deleting container {}", name);
+        let (status, body) = self
+            .request(
+                Method::DELETE,
+                &format!("/containers/{}?force=true", name),
+                None,
+            )
+            .await?;
+        match status {
+            StatusCode::NOT_FOUND => Ok(()),
+            _ => check_status(status, &body).map(|_| ()),
+        }
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for DockerRuntime {
+    async fn start_container(&self, target: &TestTarget<'_>) -> Result<String, RuntimeError> {
+        let name = target.name();
+        let docker_target = target.docker_target();
+        self.delete_container_if_present(&name).await?;
+
+        println!("Starting container {} with image {}", &name, &docker_target);
+        let memory_bytes = target.ram_mb * 1024 * 1024;
+        let port = format!("{}/tcp", target.container_port);
+        let create = serde_json::json!({
+            "Image": &docker_target,
+            "Labels": { BENCH_LABEL: &name },
+            "ExposedPorts": { &port: {} },
+            "HostConfig": {
+                "Memory": memory_bytes,
+                "MemorySwap": memory_bytes,
+                "NanoCpus": target.num_cpus * 1_000_000_000,
+                "CpusetCpus": target.pinned_server_cpus().map(|cpus| cpus.to_string()),
+                "PortBindings": { &port: [{ "HostPort": target.host_port.to_string() }] },
+            },
+        });
+        let (status, body) = self
+            .request(
+                Method::POST,
+                &format!("/containers/create?name={}", name),
+                Some(create),
+            )
+            .await?;
+        if status == StatusCode::NOT_FOUND {
+            return Err(RuntimeError::ImageNotFound(docker_target));
+        }
+        let created: CreateResponse = serde_json::from_slice(check_status(status, &body)?)?;
+
+        let (status, body) = self
+            .request(
+                Method::POST,
+                &format!("/containers/{}/start", created.id),
+                None,
+            )
+            .await?;
+        if let Err(e) = check_status(status, &body) {
+            let _ = self.delete_container_if_present(&created.id).await;
+            return Err(match e {
+                RuntimeError::Api { message, .. } if is_port_conflict(&message) => {
+                    RuntimeError::PortConflict(message)
+                }
+                e => e,
+            });
+        }
+
+        Ok(created.id)
+    }
+
+    async fn stop_container(&self, id: &str) -> Result<(), RuntimeError> {
+        println!("Killing container {}", id);
+        let state = self.container_state(id).await?;
+        let (status, body) = self
+            .request(Method::POST, &format!("/containers/{}/stop", id), None)
+            .await?;
+        match status {
+            // The container was already stopped.
+            StatusCode::NOT_MODIFIED => {}
+            StatusCode::NOT_FOUND => return Err(RuntimeError::ContainerNotFound(id.to_string())),
+            _ => {
+                check_status(status, &body)?;
+            }
+        }
+        if state.is_oom_killed {
+            return Err(RuntimeError::OutOfMemory(id.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn container_state(&self, id: &str) -> Result<ContainerState, RuntimeError> {
+        let (status, body) = self
+            .request(Method::GET, &format!("/containers/{}/json", id), None)
+            .await?;
+        if status == StatusCode::NOT_FOUND {
+            return Err(RuntimeError::ContainerNotFound(id.to_string()));
+        }
+        let inspect: InspectResponse = serde_json::from_slice(check_status(status, &body)?)?;
+        Ok(inspect.state.into())
+    }
+
+    async fn container_stats(&self, id: &str) -> Result<ContainerStats, RuntimeError> {
+        let (status, body) = self
+            .request(
+                Method::GET,
+                &format!("/containers/{}/stats?stream=false", id),
+                None,
+            )
+            .await?;
+        if status == StatusCode::NOT_FOUND {
+            return Err(RuntimeError::ContainerNotFound(id.to_string()));
+        }
+        let stats: StatsResponse = serde_json::from_slice(check_status(status, &body)?)?;
+        Ok(stats.into())
+    }
+
+    async fn remove_container(&self, id: &str) -> Result<(), RuntimeError> {
+        self.delete_container_if_present(id).await
+    }
+
+    async fn container_logs(&self, id: &str, tail: Option<usize>) -> Result<String, RuntimeError> {
+        let tail = tail.map_or(String::from("all"), |tail| tail.to_string());
+        let (status, body) = self
+            .request(
+                Method::GET,
+                &format!(
+                    "/containers/{}/logs?stdout=true&stderr=true&tail={}",
+                    id, tail
+                ),
+                None,
+            )
+            .await?;
+        if status == StatusCode::NOT_FOUND {
+            return Err(RuntimeError::ContainerNotFound(id.to_string()));
+        }
+        Ok(demux_logs(check_status(status, &body)?))
+    }
+
+    async fn inspect_image(
+        &self,
+        target: &TestTarget<'_>,
+    ) -> Result<Option<ImageInfo>, RuntimeError> {
+        let image = target.docker_target();
+        let (status, body) = self
+            .request(Method::GET, &format!("/images/{}/json", image), None)
+            .await?;
+        if status == StatusCode::NOT_FOUND {
+            return Err(RuntimeError::ImageNotFound(image));
+        }
+        let inspect: ImageInspectResponse = serde_json::from_slice(check_status(status, &body)?)?;
+        Ok(Some(inspect.into()))
+    }
+
+    /// Builds the image, tagging it with the name containers are started from. The build's
+    /// progress is streamed back as JSON messages, which hold any error, as the status code is
+    /// sent before the build starts.
+    async fn build_image(
+        &self,
+        target: &TestTarget<'_>,
+        context_dir: &Path,
+    ) -> Result<bool, RuntimeError> {
+        let image = target.docker_target();
+        println!("Building image {} from {}", image, context_dir.display());
+        let context = tar_dir(context_dir).map_err(|e| RuntimeError::BuildFailed {
+            image: image.clone(),
+            output: format!("Could not read {}: {}", context_dir.display(), e),
+        })?;
+        let (status, body) = self
+            .send(
+                Method::POST,
+                &format!("/build?t={}&rm=true&forcerm=true", percent_encode(&image)),
+                "application/x-tar",
+                Body::from(context),
+            )
+            .await?;
+        build_output(check_status(status, &body)?)
+            .map(|_| true)
+            .map_err(|output| RuntimeError::BuildFailed { image, output })
+    }
+
+    async fn version(&self) -> Result<Option<String>, RuntimeError> {
+        let (status, body) = self.request(Method::GET, "/version", None).await?;
+        let version: VersionResponse = serde_json::from_slice(check_status(status, &body)?)?;
+        Ok(Some(version.version))
+    }
+
+    async fn remove_stale_containers(&self) -> Result<Vec<String>, RuntimeError> {
+        let filters = serde_json::json!({ "label": [BENCH_LABEL] }).to_string();
+        let (status, body) = self
+            .request(
+                Method::GET,
+                &format!(
+                    "/containers/json?all=true&filters={}",
+                    percent_encode(&filters)
+                ),
+                None,
+            )
+            .await?;
+        let containers: Vec<ListedContainer> =
+            serde_json::from_slice(check_status(status, &body)?)?;
+        let mut removed = vec![];
+        for container in containers {
+            self.delete_container_if_present(&container.id).await?;
+            removed.push(container.id);
+        }
+        Ok(removed)
+    }
+}
 
-    Ok(name)
+/// Percent-encodes everything but unreserved characters, for use in a query string.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
 }
 
-pub async fn is_healthy() -> bool {
-    let hello = reqwest::get("http://localhost:8080/strings/hello").await;
-    match hello {
-        Ok(r) => r.status().is_success(),
-        _ => false,
+/// Archives a directory's contents, to send as a build context, leaving out whatever its
+/// `.dockerignore` excludes.
+fn tar_dir(dir: &Path) -> io::Result<Vec<u8>> {
+    let ignore = match fs::read_to_string(dir.join(".dockerignore")) {
+        Ok(contents) => DockerIgnore::parse(&contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => DockerIgnore::default(),
+        Err(e) => return Err(e),
+    };
+    let mut archive = tar::Builder::new(vec![]);
+    archive.follow_symlinks(false);
+    append_dir_contents(&mut archive, dir, "", &ignore)?;
+    archive.into_inner()
+}
+
+/// Appends the entries under `dir`, named relative to the context root by `prefix`, skipping
+/// ignored ones. Ignored directories are still walked if a later exception could re-include
+/// something inside them.
+fn append_dir_contents(
+    archive: &mut tar::Builder<Vec<u8>>,
+    dir: &Path,
+    prefix: &str,
+    ignore: &DockerIgnore,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let ignored = ignore.is_ignored(&name);
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if !ignored {
+                archive.append_dir(&name, &path)?;
+            }
+            if !ignored || ignore.has_exceptions() {
+                append_dir_contents(archive, &path, &format!("{}/", name), ignore)?;
+            }
+        } else if !ignored {
+            archive.append_path_with_name(&path, &name)?;
+        }
     }
+    Ok(())
 }
 
-pub async fn await_healthy() {
-    println!("Polling until healthy");
-    loop {
-        if is_healthy().await {
-            break;
+/// The patterns of a `.dockerignore` file, in order. Later patterns take precedence, and those
+/// starting with `!` are exceptions that re-include what earlier ones excluded.
+#[derive(Debug, Default)]
+struct DockerIgnore {
+    patterns: Vec<(Regex, bool)>,
+}
+
+impl DockerIgnore {
+    fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (pattern, exception) = match line.strip_prefix('!') {
+                    Some(pattern) => (pattern.trim(), true),
+                    None => (line, false),
+                };
+                let pattern = clean_pattern(pattern);
+                (!pattern.is_empty())
+                    .then(|| Regex::new(&pattern_regex(&pattern)).ok())
+                    .flatten()
+                    .map(|regex| (regex, exception))
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|(_, exception)| *exception)
+    }
+
+    /// Whether the path, relative to the context root, is excluded. A pattern matching any of
+    /// its parent directories matches it too. The Dockerfile and `.dockerignore` are always sent,
+    /// as the daemon needs them.
+    fn is_ignored(&self, path: &str) -> bool {
+        if path == "Dockerfile" || path == ".dockerignore" {
+            return false;
         }
+        let mut ignored = false;
+        for (regex, exception) in &self.patterns {
+            let matches = regex.is_match(path)
+                || path
+                    .match_indices('/')
+                    .any(|(i, _)| regex.is_match(&path[..i]));
+            if matches {
+                ignored = !exception;
+            }
+        }
+        ignored
     }
-    println!("Container is ready");
 }
 
-pub fn kill_container(name: &str) -> Result<(), Error> {
-    println!("Killing container {}", name);
-    let status = Command::new("docker")
-        .args(["stop", &name])
-        .spawn()?
-        .wait()?;
+/// Normalizes a pattern the way paths in the context are named: relative to its root, without
+/// `.` components or a trailing slash.
+fn clean_pattern(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
 
-    if status.success() {
-        return Ok(());
-    } else {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Exited with status code {}", status),
-        ));
+/// Translates a pattern into an anchored regex. `*` and `?` don't match across directories,
+/// while `**` matches any number of them, including none.
+fn pattern_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                }
+                regex.push_str(if chars.peek().is_none() {
+                    ".*"
+                } else {
+                    "(.*/)?"
+                });
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    regex.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
     }
+    regex.push('$');
+    regex
 }
 
-fn delete_container_if_present(name: &str) -> Result<(), Error> {
-    println!("Deleting container {}", name);
-    Command::new("docker")
-        .args(["rm", "-f", name])
-        .spawn()?
-        .wait()?;
-    Ok(())
+/// Joins the output of a build's progress messages, failing with the output so far and the error
+/// if the build failed.
+fn build_output(messages: &[u8]) -> Result<String, String> {
+    let mut output = String::new();
+    for message in serde_json::Deserializer::from_slice(messages).into_iter::<BuildMessage>() {
+        let message = message.map_err(|e| format!("{}Unreadable build output: {}", output, e))?;
+        if let Some(stream) = message.stream {
+            output.push_str(&stream);
+        }
+        if let Some(error) = message.error {
+            output.push_str(&error);
+            return Err(output);
+        }
+    }
+    Ok(output)
+}
+
+/// Joins the stdout and stderr frames of a multiplexed log stream into one string.
+///
+/// Each frame has an 8 byte header, holding the stream type and then the frame's length as a big
+/// endian u32 in its last 4 bytes.
+fn demux_logs(mut stream: &[u8]) -> String {
+    let mut logs = vec![];
+    while stream.len() >= 8 {
+        let len = u32::from_be_bytes([stream[4], stream[5], stream[6], stream[7]]) as usize;
+        let end = (8 + len).min(stream.len());
+        logs.extend_from_slice(&stream[8..end]);
+        stream = &stream[end..];
+    }
+    String::from_utf8_lossy(&logs).into_owned()
+}
+
+/// Returns the body of a successful response, or the error message from a failed one.
+fn check_status(status: StatusCode, body: &[u8]) -> Result<&[u8], RuntimeError> {
+    if status.is_success() {
+        return Ok(body);
+    }
+    let message = serde_json::from_slice::<ErrorResponse>(body)
+        .map(|e| e.message)
+        .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned());
+    Err(RuntimeError::Api {
+        status: status.as_u16(),
+        message,
+    })
+}
+
+fn is_port_conflict(message: &str) -> bool {
+    message.contains("port is already allocated") || message.contains("address already in use")
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VersionResponse {
+    version: String,
+}
+
+#[derive(serde::Deserialize)]
+struct BuildMessage {
+    stream: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateResponse {
+    id: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedContainer {
+    id: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ImageInspectResponse {
+    id: String,
+    #[serde(default)]
+    repo_digests: Vec<String>,
+    size: u64,
+    created: String,
+    #[serde(rename = "RootFS")]
+    root_fs: RootFs,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RootFs {
+    #[serde(default)]
+    layers: Vec<String>,
+}
+
+impl From<ImageInspectResponse> for ImageInfo {
+    fn from(image: ImageInspectResponse) -> Self {
+        ImageInfo {
+            id: image.id,
+            digest: image.repo_digests.into_iter().next(),
+            size_bytes: image.size,
+            num_layers: image.root_fs.layers.len(),
+            created: image.created,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectResponse {
+    state: InspectState,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectState {
+    running: bool,
+    status: String,
+    exit_code: i64,
+    #[serde(rename = "OOMKilled")]
+    oom_killed: bool,
+}
+
+impl From<InspectState> for ContainerState {
+    fn from(state: InspectState) -> Self {
+        // Docker reports an exit code of 0 for containers that have not exited.
+        let has_exited = state.status == "exited" || state.status == "dead";
+        ContainerState {
+            is_running: state.running,
+            exit_code: has_exited.then_some(state.exit_code),
+            is_oom_killed: state.oom_killed,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct StatsResponse {
+    cpu_stats: CpuStats,
+    precpu_stats: CpuStats,
+    memory_stats: MemoryStats,
+    #[serde(default)]
+    networks: HashMap<String, NetworkStats>,
+}
+
+#[derive(serde::Deserialize)]
+struct CpuStats {
+    cpu_usage: CpuUsage,
+    #[serde(default)]
+    system_cpu_usage: u64,
+    #[serde(default)]
+    online_cpus: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct CpuUsage {
+    total_usage: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct MemoryStats {
+    #[serde(default)]
+    usage: u64,
+    #[serde(default)]
+    stats: HashMap<String, u64>,
+}
+
+#[derive(serde::Deserialize)]
+struct NetworkStats {
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl From<StatsResponse> for ContainerStats {
+    /// Computes stats the same way as `docker stats`.
+    fn from(stats: StatsResponse) -> Self {
+        let cpu_delta = stats
+            .cpu_stats
+            .cpu_usage
+            .total_usage
+            .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+        let system_delta = stats
+            .cpu_stats
+            .system_cpu_usage
+            .saturating_sub(stats.precpu_stats.system_cpu_usage);
+        let cpu_percent = match system_delta {
+            0 => 0.0,
+            _ => {
+                cpu_delta as f64 / system_delta as f64 * stats.cpu_stats.online_cpus as f64 * 100.0
+            }
+        };
+        // Page cache is excluded from memory usage. cgroup v2 reports it as "inactive_file", and
+        // v1 as "total_inactive_file".
+        let cache = ["inactive_file", "total_inactive_file"]
+            .iter()
+            .find_map(|key| stats.memory_stats.stats.get(*key))
+            .copied()
+            .unwrap_or(0);
+        ContainerStats {
+            cpu_percent,
+            memory_bytes: stats.memory_stats.usage.saturating_sub(cache),
+            net_rx_bytes: stats.networks.values().map(|n| n.rx_bytes).sum(),
+            net_tx_bytes: stats.networks.values().map(|n| n.tx_bytes).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats_from_response() {
+        let response: StatsResponse = serde_json::from_str(
+            r#"{
+                "cpu_stats": {
+                    "cpu_usage": { "total_usage": 300000000 },
+                    "system_cpu_usage": 4000000000,
+                    "online_cpus": 4
+                },
+                "precpu_stats": {
+                    "cpu_usage": { "total_usage": 100000000 },
+                    "system_cpu_usage": 2000000000,
+                    "online_cpus": 4
+                },
+                "memory_stats": { "usage": 50000000, "stats": { "inactive_file": 10000000 } },
+                "networks": {
+                    "eth0": { "rx_bytes": 1200, "tx_bytes": 648 },
+                    "eth1": { "rx_bytes": 800, "tx_bytes": 352 }
+                }
+            }"#,
+        )
+        .unwrap();
+        let stats = ContainerStats::from(response);
+        assert_relative_eq!(stats.cpu_percent, 40.0);
+        assert_eq!(stats.memory_bytes, 40_000_000);
+        assert_eq!(stats.net_rx_bytes, 2_000);
+        assert_eq!(stats.net_tx_bytes, 1_000);
+    }
+
+    #[test]
+    fn test_demux_logs() {
+        let mut stream = vec![1, 0, 0, 0, 0, 0, 0, 6];
+        stream.extend_from_slice(b"ready\n");
+        stream.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 6]);
+        stream.extend_from_slice(b"oops!\n");
+        assert_eq!(demux_logs(&stream), "ready\noops!\n");
+    }
+
+    #[test]
+    fn test_build_output() {
+        let messages = br#"{"stream":"Step 1/2 : FROM scratch\n"}
+{"stream":"Step 2/2 : COPY hello /\n"}{"aux":{"ID":"sha256:abc"}}
+"#;
+        assert_eq!(
+            build_output(messages),
+            Ok(String::from(
+                "Step 1/2 : FROM scratch\nStep 2/2 : COPY hello /\n"
+            ))
+        );
+
+        let messages = br#"{"stream":"Step 1/2 : FROM scratch\n"}
+{"errorDetail":{"message":"COPY failed"},"error":"COPY failed"}
+"#;
+        assert_eq!(
+            build_output(messages),
+            Err(String::from("Step 1/2 : FROM scratch\nCOPY failed"))
+        );
+    }
+
+    #[test]
+    fn test_image_info_from_inspect() {
+        let response: ImageInspectResponse = serde_json::from_str(
+            r#"{
+                "Id": "sha256:abc",
+                "RepoDigests": ["hello-rust-axum@sha256:def"],
+                "Size": 12345678,
+                "Created": "2023-04-01T12:00:00.000000000Z",
+                "RootFS": { "Type": "layers", "Layers": ["sha256:1", "sha256:2"] }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ImageInfo::from(response),
+            ImageInfo {
+                id: String::from("sha256:abc"),
+                digest: Some(String::from("hello-rust-axum@sha256:def")),
+                size_bytes: 12_345_678,
+                num_layers: 2,
+                created: String::from("2023-04-01T12:00:00.000000000Z"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_docker_ignore() {
+        let ignore = DockerIgnore::parse(
+            "# build output\ntarget\n/node_modules/\n*.log\n!keep.log\n**/*.tmp\n\nDockerfile\n",
+        );
+        assert!(ignore.is_ignored("target"));
+        assert!(ignore.is_ignored("target/release/hello"));
+        assert!(ignore.is_ignored("node_modules/express/index.js"));
+        assert!(ignore.is_ignored("server.log"));
+        assert!(!ignore.is_ignored("keep.log"));
+        assert!(!ignore.is_ignored("logs/server.log"));
+        assert!(ignore.is_ignored("scratch.tmp"));
+        assert!(ignore.is_ignored("src/scratch.tmp"));
+        assert!(!ignore.is_ignored("src/main.rs"));
+        assert!(!ignore.is_ignored("Dockerfile"));
+        assert!(ignore.has_exceptions());
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(
+            percent_encode(r#"{"label":["hello-world-bench"]}"#),
+            "%7B%22label%22%3A%5B%22hello-world-bench%22%5D%7D"
+        );
+    }
+
+    #[test]
+    fn test_state_from_inspect() {
+        let inspect: InspectResponse = serde_json::from_str(
+            r#"{ "State": { "Running": false, "Status": "exited", "ExitCode": 137, "OOMKilled": true } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ContainerState::from(inspect.state),
+            ContainerState {
+                is_running: false,
+                exit_code: Some(137),
+                is_oom_killed: true,
+            }
+        );
+    }
 }
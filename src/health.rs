@@ -0,0 +1,241 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::HealthConfig,
+    runtime::{Container, ContainerRuntime, RuntimeError, Runtimes},
+    targets::TestTarget,
+};
+
+/// The number of log lines to report when a container exits during startup.
+const LOG_TAIL_LINES: usize = 20;
+/// The shortest time to wait for a response to a health check, unless the startup timeout is
+/// sooner.
+const MIN_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What to do with a target whose container fails to start or doesn't become healthy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnhealthyPolicy {
+    /// Stops the benchmark with an error.
+    #[default]
+    Fail,
+    /// Moves on to the next target.
+    Skip,
+}
+
+#[derive(Debug)]
+pub enum HealthError {
+    /// The container didn't become healthy within the startup timeout.
+    Timeout {
+        target: String,
+        timeout: Duration,
+    },
+    /// The container exited before becoming healthy.
+    Exited {
+        target: String,
+        exit_code: Option<i64>,
+        is_oom_killed: bool,
+        /// The last lines the container logged.
+        logs: String,
+    },
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for HealthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthError::Timeout { target, timeout } => {
+                write!(f, "Target {} was not healthy after {:?}", target, timeout)
+            }
+            HealthError::Exited {
+                target,
+                exit_code,
+                is_oom_killed,
+                logs,
+            } => {
+                write!(f, "Target {} exited during startup", target)?;
+                if let Some(code) = exit_code {
+                    write!(f, " with code {}", code)?;
+                }
+                if *is_oom_killed {
+                    write!(f, " after running out of memory")?;
+                }
+                write!(f, ". Last log lines:\n{}", logs)
+            }
+            HealthError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for HealthError {}
+
+impl From<RuntimeError> for HealthError {
+    fn from(e: RuntimeError) -> Self {
+        HealthError::Runtime(e)
+    }
+}
+
+/// Gets how long to wait for a response to a health check, which is the maximum poll interval, so
+/// that a server that never responds is polled as often as one that refuses connections.
+pub fn check_timeout(config: &HealthConfig) -> Duration {
+    Duration::from_millis(config.max_poll_interval_ms).max(MIN_CHECK_TIMEOUT)
+}
+
+/// Sends the target's health check, returning whether it got the expected response within the
+/// timeout.
+pub async fn is_healthy(
+    client: &reqwest::Client,
+    target: &TestTarget<'_>,
+    timeout: Duration,
+) -> bool {
+    let check = target.health_check;
+    let request = client.get(target.url(&check.path)).timeout(timeout);
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(_) => return false,
+    };
+    let status = response.status().as_u16();
+    // Only read the body if it needs to be checked.
+    let body = match check.body_contains {
+        Some(_) => response.text().await.unwrap_or_default(),
+        None => String::new(),
+    };
+    check.matches(status, &body)
+}
+
+/// Polls the container until it is healthy.
+pub async fn await_healthy(
+    runtime: &dyn ContainerRuntime,
+    id: &str,
+    target: &TestTarget<'_>,
+    config: &HealthConfig,
+) -> Result<(), HealthError> {
+    println!("Polling {} until healthy", target.health_check.path);
+    let client = reqwest::Client::new();
+    let client = &client;
+    let check_timeout = check_timeout(config);
+    poll_until_ready(runtime, id, target, config, |time_left| async move {
+        let timeout = check_timeout.min(time_left);
+        is_healthy(client, target, timeout).await.then_some(())
+    })
+    .await?;
+    println!("Container is ready");
+    Ok(())
+}
+
+/// Calls `probe` until it returns a value, which is then returned. `probe` is given the time left
+/// before the startup timeout, which it shouldn't take longer than.
+///
+/// The delay between attempts starts at the poll interval, and doubles after each attempt up to the
+/// maximum. Fails if the container exits or the startup timeout passes first.
+pub async fn poll_until_ready<T, F, Fut>(
+    runtime: &dyn ContainerRuntime,
+    id: &str,
+    target: &TestTarget<'_>,
+    config: &HealthConfig,
+    mut probe: F,
+) -> Result<T, HealthError>
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let start = Instant::now();
+    let timeout = Duration::from_secs(config.startup_timeout_secs);
+    let max_interval = Duration::from_millis(config.max_poll_interval_ms);
+    let mut interval = Duration::from_millis(config.poll_interval_ms).min(max_interval);
+    loop {
+        if let Some(value) = probe(timeout.saturating_sub(start.elapsed())).await {
+            return Ok(value);
+        }
+
+        let state = runtime.container_state(id).await?;
+        if !state.is_running {
+            return Err(HealthError::Exited {
+                target: target.name(),
+                exit_code: state.exit_code,
+                is_oom_killed: state.is_oom_killed,
+                logs: runtime
+                    .container_logs(id, Some(LOG_TAIL_LINES))
+                    .await
+                    .unwrap_or_else(|e| format!("<unavailable: {}>", e)),
+            });
+        }
+        if start.elapsed() >= timeout {
+            return Err(HealthError::Timeout {
+                target: target.name(),
+                timeout,
+            });
+        }
+
+        tokio::time::sleep(interval).await;
+        interval = (interval * 2).min(max_interval);
+    }
+}
+
+/// Starts a container for the target.
+///
+/// If it fails to start, either `None` is returned so that the target can be skipped, or the error
+/// is returned, depending on the configured policy.
+pub async fn start(
+    runtimes: &Runtimes,
+    target: &TestTarget<'_>,
+    log_path: &Path,
+    config: &HealthConfig,
+) -> Result<Option<Container>, Box<dyn Error>> {
+    match runtimes.start(target, log_path).await {
+        Ok(container) => Ok(Some(container)),
+        Err(e) => apply_policy(config, e.into()).map(|_| None),
+    }
+}
+
+/// Starts a container for the target, and waits for it to become healthy.
+///
+/// If it fails to start, or doesn't become healthy, the container is stopped, and either `None` is
+/// returned so that the target can be skipped, or the error is returned, depending on the
+/// configured policy.
+pub async fn start_healthy(
+    runtimes: &Runtimes,
+    target: &TestTarget<'_>,
+    log_path: &Path,
+    config: &HealthConfig,
+) -> Result<Option<Container>, Box<dyn Error>> {
+    let container = match start(runtimes, target, log_path, config).await? {
+        Some(container) => container,
+        None => return Ok(None),
+    };
+    let health = await_healthy(container.runtime().as_ref(), &container.id, target, config).await;
+    match health {
+        Ok(()) => Ok(Some(container)),
+        Err(e) => handle_unhealthy(container, config, e).await.map(|_| None),
+    }
+}
+
+/// Stops a container that failed to become healthy, then applies the configured policy.
+///
+/// Returns `Ok` if the target should be skipped.
+pub async fn handle_unhealthy(
+    container: Container,
+    config: &HealthConfig,
+    error: HealthError,
+) -> Result<(), Box<dyn Error>> {
+    // The container may have already exited, or been killed for running out of memory.
+    let _ = container.stop().await;
+    apply_policy(config, error)
+}
+
+/// Returns the error, or logs it and returns `Ok` if the target should be skipped.
+fn apply_policy(config: &HealthConfig, error: HealthError) -> Result<(), Box<dyn Error>> {
+    match config.on_failure {
+        UnhealthyPolicy::Fail => Err(error.into()),
+        UnhealthyPolicy::Skip => {
+            eprintln!("Skipping target: {}", error);
+            Ok(())
+        }
+    }
+}
@@ -1,59 +1,83 @@
-use std::{error::Error, path::PathBuf, time::Duration};
-
-use goose::{config::GooseConfiguration, logger::GooseLogFormat, prelude::*};
-
-use crate::{docker, targets::TestTarget};
+use std::{error::Error, io, path::PathBuf, sync::Arc, time::Duration};
+
+use goose::{
+    config::GooseConfiguration, logger::GooseLogFormat, metrics::GooseMetrics, prelude::*,
+};
+
+use crate::{
+    config::{HealthConfig, LoadConfig, LoadTransaction, NoiseConfig},
+    health,
+    noise::NoiseMonitor,
+    runtime::{self, Runtimes},
+    stats::ResourceSampler,
+    targets::{Encoding, TestTarget},
+    writes,
+};
 
 const REQUEST_LOG_FORMAT: GooseLogFormat = GooseLogFormat::Csv;
 static APP_USER_AGENT: &str = "http-load-tester/0.0.1";
 
-async fn configure_user_without_compression(user: &mut GooseUser) -> TransactionResult {
-    let builder = reqwest::Client::builder()
-        .user_agent(APP_USER_AGENT)
-        .no_brotli()
-        .no_gzip()
-        .timeout(Duration::from_secs(10));
-    user.set_client_builder(builder).await?;
-    Ok(())
-}
-
-async fn configure_user_with_compression(user: &mut GooseUser) -> TransactionResult {
-    let builder = reqwest::Client::builder()
-        .user_agent(APP_USER_AGENT)
-        .gzip(true)
-        .brotli(true)
-        .timeout(Duration::from_secs(10));
-    user.set_client_builder(builder).await?;
-    Ok(())
+/// Builds a transaction that configures each user to accept the given encoding.
+fn configure_user(encoding: Encoding) -> Transaction {
+    Transaction::new(Arc::new(move |user| {
+        Box::pin(async move {
+            let builder = reqwest::Client::builder()
+                .user_agent(APP_USER_AGENT)
+                .timeout(Duration::from_secs(10));
+            user.set_client_builder(encoding.configure_client(builder))
+                .await?;
+            Ok(())
+        })
+    }))
 }
 
-async fn loadtest_strings(user: &mut GooseUser) -> TransactionResult {
-    let _goose_metrics = user.get_named("/strings/hello", "hello").await?;
-    let _goose_metrics = user.get_named("/strings/hello?name=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "hello-long").await?;
-    let _goose_metrics = user
-        .get_named("/strings/async-hello", "async-hello")
-        .await?;
-    let _goose_metrics = user.get_named("/strings/lines?n=10000", "lines").await?;
+/// Builds a transaction that requests each of the given paths in sequence.
+fn loadtest_paths(transaction: &LoadTransaction) -> Transaction {
+    let paths = Arc::new(transaction.paths.clone());
+    Transaction::new(Arc::new(move |user| {
+        let paths = paths.clone();
+        Box::pin(async move {
+            for path in paths.iter() {
+                let _goose_metrics = user.get_named(&path.path, &path.name).await?;
+            }
 
-    Ok(())
+            Ok(())
+        })
+    }))
+    .set_name(&transaction.name)
 }
 
-async fn loadtest_static(user: &mut GooseUser) -> TransactionResult {
-    let _goose_metrics = user.get_named("/static/basic.html", "basic-html").await?;
-    let _goose_metrics = user.get_named("/static/scout.webp", "scout-img").await?;
+/// The process's SIGINT handler, saved so that it can be put back after goose replaces it.
+struct SavedSigint(libc::sigaction);
 
-    Ok(())
-}
+impl SavedSigint {
+    fn save() -> io::Result<Self> {
+        // SAFETY: sigaction is plain data, and passing no new action only reads the current one.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(action))
+        }
+    }
 
-async fn loadtest_math(user: &mut GooseUser) -> TransactionResult {
-    let _goose_metrics = user
-        .get_named("/math/power-reciprocals-alt?n=1000", "power-sum-easy")
-        .await?;
-    let _goose_metrics = user
-        .get_named("/math/power-reciprocals-alt?n=10000000", "power-sum-hard")
-        .await?;
+    fn restore(&self) -> io::Result<()> {
+        // SAFETY: the action was read by sigaction, so it's valid to set again.
+        if unsafe { libc::sigaction(libc::SIGINT, &self.0, std::ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
 
-    Ok(())
+/// Whether goose stopped the load test early because of a Ctrl-C.
+fn was_canceled(metrics: &GooseMetrics) -> bool {
+    // goose doesn't export the type of the step's action, so it can only be matched by name.
+    metrics
+        .history
+        .iter()
+        .any(|step| format!("{:?}", step.action) == "Canceling")
 }
 
 fn report_log_path(mut out_dir: PathBuf, iteration: usize) -> String {
@@ -70,33 +94,36 @@ async fn bench_target(
     tt: &TestTarget<'_>,
     out_dir: PathBuf,
     iteration: usize,
+    config: &LoadConfig,
 ) -> Result<(), Box<dyn Error>> {
     let mut configuration = GooseConfiguration::default();
-    configuration.host = "http://localhost:8080".to_string();
-    configuration.users = Some(6);
-    configuration.startup_time = "60s".to_string();
-    configuration.run_time = "10s".to_string();
+    configuration.host = tt.base_url();
+    configuration.users = Some(config.users);
+    configuration.startup_time = format!("{}s", config.startup_time_secs);
+    configuration.run_time = format!("{}s", config.run_time_secs);
     configuration.report_file = report_log_path(out_dir.clone(), iteration);
     configuration.request_log = request_log_path(out_dir, iteration);
     configuration.request_format = Some(REQUEST_LOG_FORMAT);
 
-    let configure_user_fn = match tt.is_compressed {
-        true => transaction!(configure_user_with_compression),
-        false => transaction!(configure_user_without_compression),
-    };
-
     println!("Starting load test against target {}", tt.name());
 
-    GooseAttack::initialize_with_config(configuration)?
-        .register_scenario(
-            scenario!("LoadTest")
-                .register_transaction(configure_user_fn.set_on_start())
-                .register_transaction(transaction!(loadtest_strings).set_name("strings"))
-                .register_transaction(transaction!(loadtest_static).set_name("static"))
-                .register_transaction(transaction!(loadtest_math).set_name("math")),
-        )
+    let scenario = config.transactions.iter().fold(
+        scenario!("LoadTest").register_transaction(configure_user(tt.encoding).set_on_start()),
+        |scenario, transaction| scenario.register_transaction(loadtest_paths(transaction)),
+    );
+    // goose replaces the SIGINT handler with one that only stops the load test, and exits the
+    // process on a second Ctrl-C without stopping any containers. Put back the handler that stops
+    // the whole run, and stop the run too if the load test was interrupted.
+    let sigint = SavedSigint::save()?;
+    let metrics = GooseAttack::initialize_with_config(configuration)?
+        .register_scenario(scenario)
         .execute()
-        .await?;
+        .await;
+    sigint.restore()?;
+    if was_canceled(&metrics?) {
+        eprintln!("Interrupted, stopping containers");
+        return Err("Interrupted".into());
+    }
 
     println!("Finished load test against target {}", tt.name());
 
@@ -104,13 +131,35 @@ async fn bench_target(
 }
 
 /// Benchmarks each target with a load test, producing an HTML report and requests CSV for each iteration.
+///
+/// The resource usage of each target during each iteration, and how busy the host was around it, are
+/// written to CSVs in out_dir.
 pub async fn benchmark_all(
     targets: &Vec<TestTarget<'_>>,
     out_dir: PathBuf,
+    config: &LoadConfig,
+    health_config: &HealthConfig,
+    noise_config: &NoiseConfig,
+    runtimes: &Runtimes,
 ) -> Result<(), Box<dyn Error>> {
-    for target in targets {
-        let name = docker::start_container(target)?;
-        docker::await_healthy().await;
+    let mut samples_csv_path = out_dir.clone();
+    samples_csv_path.push("resource-samples.csv");
+    let mut samples_csv = csv::Writer::from_path(&samples_csv_path)?;
+    let mut summary_csv_path = out_dir.clone();
+    summary_csv_path.push("resource-summary.csv");
+    let mut summary_csv = csv::Writer::from_path(&summary_csv_path)?;
+    let mut noise_csv_path = out_dir.clone();
+    noise_csv_path.push("noise.csv");
+    let mut noise_csv = csv::Writer::from_path(&noise_csv_path)?;
+
+    'targets: for target in targets {
+        let target = &target.with_host_port()?;
+        let log_path = runtime::prep_log_path(&out_dir, target)?;
+        let Some(mut container) =
+            health::start_healthy(runtimes, target, &log_path, health_config).await?
+        else {
+            continue;
+        };
 
         let mut target_dir = out_dir.clone();
         target_dir.push(target.name());
@@ -118,17 +167,27 @@ pub async fn benchmark_all(
             tokio::fs::create_dir_all(&target_dir).await?;
         }
 
-        for i in 1..=3 {
-            bench_target(target, target_dir.clone(), i).await?;
+        for i in 1..=config.iterations {
+            let run = format!("iteration-{}", i);
+            let monitor = NoiseMonitor::start(target, noise_config).await?;
+            let sampler = ResourceSampler::start(container.runtime().clone(), &container.id);
+            bench_target(target, target_dir.clone(), i, config).await?;
+            let usage = sampler.stop(target.clone(), run.clone()).await;
+            let noise = monitor.stop(target.clone(), run).await?;
+            writes::write_noise(&mut noise_csv, &noise)?;
+            writes::write_resource_usage(&mut samples_csv, &mut summary_csv, &usage)?;
             tokio::time::sleep(Duration::from_secs(5)).await;
-            if !docker::is_healthy().await {
-                docker::kill_container(&name).unwrap_or(());
-                docker::start_container(target)?;
-                docker::await_healthy().await;
+            let check_timeout = health::check_timeout(health_config);
+            if !health::is_healthy(&reqwest::Client::new(), target, check_timeout).await {
+                container.stop().await.unwrap_or(());
+                match health::start_healthy(runtimes, target, &log_path, health_config).await? {
+                    Some(new_container) => container = new_container,
+                    None => continue 'targets,
+                }
             }
         }
 
-        docker::kill_container(&name)?;
+        container.stop().await?;
     }
 
     Ok(())
@@ -6,90 +6,336 @@ extern crate lazy_static;
 extern crate approx;
 
 use std::{
+    collections::HashMap,
     error::Error,
     fs,
     path::{Path, PathBuf},
 };
 
-use clap::Parser;
-use targets::TestTarget;
+use clap::{Parser, Subcommand};
+use config::{Config, TargetConfig};
+use cpuset::CpuSet;
+use health::UnhealthyPolicy;
+use manifest::RunManifest;
+use perf::Engine;
+use runtime::{RuntimeKind, Runtimes};
+use targets::{Compression, TestTarget};
 
+mod capacity;
+mod config;
+mod cpuset;
 mod docker;
+mod health;
 mod load;
+mod manifest;
 mod metrics;
+mod native;
+mod noise;
 mod paths;
 mod perf;
+mod process;
+mod remote;
+mod runtime;
+mod stats;
 mod targets;
 mod warm_up;
 mod writes;
 
 /// Runs benchmarks for specified hello-world servers.
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, serde::Serialize)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// A TOML file configuring the targets, paths and parameters of each benchmark. Other options
+    /// override the values in this file.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
     /// The list of targets to benchmark, in the form "lang-framework". These must match docker
     /// images with tags "hell-lang-framework". Can be specified multiple times.
     #[arg(short, long)]
     pub targets: Vec<String>,
-    ///If specified, tests results with compression enabled.
+    /// The container runtime to run targets given with --targets in. Defaults to docker. Targets
+    /// run as local processes must be given in the config file, along with their command.
+    #[arg(long, value_enum)]
+    pub runtime: Option<RuntimeKind>,
+    /// The host port to publish every target on, or 0 to pick a free port for each one when it is
+    /// started. Defaults to 8080.
+    #[arg(long)]
+    pub host_port: Option<u16>,
+    /// The compression settings to test, separated by commas. Each target is tested with each
+    /// setting separately. Defaults to none.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub compression: Vec<Compression>,
+    /// How long, in seconds, each server may take to become healthy after starting. Defaults to 60.
+    #[arg(long)]
+    pub startup_timeout_secs: Option<u64>,
+    /// If specified, targets that fail to start or don't become healthy are skipped, rather than
+    /// stopping the benchmark.
     #[arg(long)]
-    pub compress: bool,
+    pub skip_unhealthy: bool,
+    /// If specified, builds each target's image before benchmarking, from the Dockerfile in the
+    /// subdirectory of this directory named after its server, e.g. "<DIR>/rust-axum".
+    #[arg(long, value_name = "DIR")]
+    pub build: Option<PathBuf>,
+    /// If specified, perf and load benchmarks stop when other processes are using the host's CPU,
+    /// rather than flagging the affected results.
+    #[arg(long)]
+    pub strict_environment: bool,
     /// If specified, runs performance benchmarks for individual requests.
     #[arg(long)]
     pub perf: bool,
+    /// The engine used to send requests in performance benchmarks. Defaults to wrk.
+    #[arg(long, value_enum)]
+    pub engine: Option<Engine>,
+    /// If specified, performance benchmarks also send requests at this constant rate (requests
+    /// per second), measuring latency from when each request should have been sent.
+    #[arg(long)]
+    pub rate: Option<f64>,
     /// If specified, runs load tests.
     #[arg(long)]
     pub load: bool,
+    /// If specified, searches for the maximum rate each target can sustain on each path without
+    /// violating the SLO.
+    #[arg(long)]
+    pub capacity: bool,
+    /// The p99 latency, in ms, that a target must stay within during the capacity search. Defaults
+    /// to 100.
+    #[arg(long)]
+    pub slo_p99_ms: Option<u64>,
+    /// The fraction of requests (from 0 to 1) that may fail during the capacity search. Defaults to
+    /// 0.01.
+    #[arg(long)]
+    pub slo_max_error_rate: Option<f64>,
     /// If specified, runs a warm-up benchmark.
     #[arg(long)]
     pub warm_up: bool,
     /// Where to write the output data.
-    #[arg(short, long)]
-    pub out_dir: String,
-    /// The number of CPUs to run the image with.
-    #[arg(long, default_value = "1")]
-    pub num_cpus: usize,
+    #[arg(short, long, required = true)]
+    pub out_dir: Option<String>,
+    /// The numbers of CPUs to run each image with, separated by commas. Each target is run with
+    /// every combination of CPUs and RAM. Defaults to 1.
+    #[arg(long, value_delimiter = ',')]
+    pub num_cpus: Vec<usize>,
+    /// The amounts of RAM, in MB, to run each image with, separated by commas. Defaults to 128.
+    #[arg(long, value_delimiter = ',')]
+    pub ram_mb: Vec<usize>,
+    /// The CPUs to pin each server to, e.g. "0-3". Each server's CPUs are taken from this set.
+    #[arg(long)]
+    pub server_cpuset: Option<CpuSet>,
+    /// The CPUs to pin the load generator to, including wrk, e.g. "4-7". Must not overlap the
+    /// server cpuset.
+    #[arg(long)]
+    pub client_cpuset: Option<CpuSet>,
+}
+
+#[derive(Subcommand, Debug, serde::Serialize)]
+pub enum Command {
+    /// Removes benchmark containers left behind by earlier runs that were killed.
+    Cleanup,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
+    let runtimes = Runtimes::from_env();
+
+    if let Some(Command::Cleanup) = args.command {
+        let removed = runtimes.remove_stale_containers().await;
+        println!("Removed {} containers", removed.len());
+        return Ok(());
+    }
+
+    // Dropping the run's future on Ctrl-C drops the guards of any running containers, which then
+    // get stopped before exiting.
+    let result = tokio::select! {
+        result = run(&args, &runtimes) => result,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("Interrupted, stopping containers");
+            Err("Interrupted".into())
+        }
+    };
+    runtimes.finish_cleanup().await;
+    result
+}
+
+async fn run(args: &Cli, runtimes: &Runtimes) -> Result<(), Box<dyn Error>> {
+    let config = load_config(args)?;
+
+    let out_dir = prep_out_dir(args.out_dir.as_deref().unwrap())?;
+    let mut manifest = RunManifest::start(args, &config);
+    manifest.container_runtimes = runtimes.versions().await;
+    if let Some(cpuset) = &config.resources.client_cpuset {
+        cpuset.pin_current_process()?;
+        println!("Pinned the load generator to CPUs {}", cpuset);
+    }
+    let targets = targets::expand_matrix(
+        &config.targets,
+        &config.resources.num_cpus,
+        &config.resources.ram_mb,
+        &config.compression,
+    );
+    if args.warm_up {
+        warm_up::check_targets(&targets)?;
+    }
 
-    let out_dir = prep_out_dir(&args.out_dir)?;
-    let targets = args
-        .targets
-        .iter()
-        .map(|t| TestTarget {
-            server_name: t,
-            ram_mb: 128,
-            num_cpus: args.num_cpus,
-            is_compressed: args.compress,
+    let build_times = match &args.build {
+        Some(source_dir) => runtimes.build_images(&targets, source_dir).await?,
+        None => HashMap::new(),
+    };
+    let images = runtimes.inspect_images(&targets).await?;
+    let targets: Vec<_> = targets
+        .into_iter()
+        .map(|target| TestTarget {
+            image: images.get(target.server_name),
+            server_cpuset: config.resources.server_cpuset.as_ref(),
+            client_cpuset: config.resources.client_cpuset.as_ref(),
+            run_id: &manifest.run_id,
+            ..target
         })
         .collect();
+    manifest.targets = targets.iter().map(Into::into).collect();
+    manifest.write(&out_dir)?;
+    println!("Starting run {}", manifest.run_id);
+    let mut images_csv = csv::Writer::from_path(out_dir.join("images.csv"))?;
+    writes::write_images(&mut images_csv, &targets)?;
+    images_csv.flush()?;
+    if args.build.is_some() {
+        let mut builds_csv = csv::Writer::from_path(out_dir.join("builds.csv"))?;
+        writes::write_builds(&mut builds_csv, &targets, &build_times)?;
+        builds_csv.flush()?;
+    }
 
     if args.perf {
         let mut perf_dir = out_dir.clone();
         perf_dir.push("perf");
         prep_out_dir(perf_dir.to_str().unwrap())?;
-        perf::benchmark_all(&targets, perf_dir).await?;
+        perf::benchmark_all(
+            &targets,
+            perf_dir,
+            &config.perf,
+            &config.health,
+            &config.noise,
+            runtimes,
+        )
+        .await?;
+    }
+
+    if args.capacity {
+        let mut capacity_dir = out_dir.clone();
+        capacity_dir.push("capacity");
+        prep_out_dir(capacity_dir.to_str().unwrap())?;
+        capacity::benchmark_all(
+            &targets,
+            capacity_dir,
+            &config.capacity,
+            &config.health,
+            runtimes,
+        )
+        .await?;
     }
 
     if args.load {
         let mut load_dir = out_dir.clone();
         load_dir.push("load");
         prep_out_dir(load_dir.to_str().unwrap())?;
-        load::benchmark_all(&targets, load_dir).await?;
+        load::benchmark_all(
+            &targets,
+            load_dir,
+            &config.load,
+            &config.health,
+            &config.noise,
+            runtimes,
+        )
+        .await?;
     }
 
     if args.warm_up {
         let mut warm_dir = out_dir.clone();
         warm_dir.push("warm_up");
         prep_out_dir(warm_dir.to_str().unwrap())?;
-        warm_up::benchmark_all(&targets, warm_dir).await?;
+        warm_up::benchmark_all(
+            &targets,
+            warm_dir,
+            &config.warm_up,
+            &config.health,
+            runtimes,
+        )
+        .await?;
     }
 
+    manifest.finished_at = Some(chrono::Utc::now());
+    manifest.write(&out_dir)?;
     Ok(())
 }
 
+/// Loads the config file, if any, and applies any overrides from the command line.
+fn load_config(args: &Cli) -> Result<Config, Box<dyn Error>> {
+    let mut config = match &args.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+
+    if !args.targets.is_empty() {
+        config.targets = args
+            .targets
+            .iter()
+            .map(|t| TargetConfig {
+                runtime: args.runtime.unwrap_or_default(),
+                ..TargetConfig::new(t.clone())
+            })
+            .collect();
+    } else if let Some(runtime) = args.runtime {
+        for target in config.targets.iter_mut() {
+            target.runtime = runtime;
+        }
+    }
+    if let Some(host_port) = args.host_port {
+        for target in config.targets.iter_mut() {
+            target.host_port = host_port;
+        }
+    }
+    if !args.compression.is_empty() {
+        config.compression = args.compression.clone();
+    }
+    if !args.num_cpus.is_empty() {
+        config.resources.num_cpus = args.num_cpus.clone();
+    }
+    if !args.ram_mb.is_empty() {
+        config.resources.ram_mb = args.ram_mb.clone();
+    }
+    if args.server_cpuset.is_some() {
+        config.resources.server_cpuset = args.server_cpuset.clone();
+    }
+    if args.client_cpuset.is_some() {
+        config.resources.client_cpuset = args.client_cpuset.clone();
+    }
+    if let Some(startup_timeout_secs) = args.startup_timeout_secs {
+        config.health.startup_timeout_secs = startup_timeout_secs;
+    }
+    if args.strict_environment {
+        config.noise.strict = true;
+    }
+    if args.skip_unhealthy {
+        config.health.on_failure = UnhealthyPolicy::Skip;
+    }
+    if let Some(engine) = args.engine {
+        config.perf.engine = engine;
+    }
+    if args.rate.is_some() {
+        config.perf.rate = args.rate;
+    }
+    if let Some(slo_p99_ms) = args.slo_p99_ms {
+        config.capacity.slo_p99_ms = slo_p99_ms;
+    }
+    if let Some(slo_max_error_rate) = args.slo_max_error_rate {
+        config.capacity.slo_max_error_rate = slo_max_error_rate;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
 fn prep_out_dir(out_dir: &str) -> Result<PathBuf, Box<dyn Error>> {
     let path = Path::new(out_dir);
     if path.is_dir() {
@@ -98,6 +344,6 @@ fn prep_out_dir(out_dir: &str) -> Result<PathBuf, Box<dyn Error>> {
     if path.exists() {
         return Err(format!("out_dir {} already exists, but is not a directory", out_dir).into());
     }
-    fs::create_dir_all(&path)?;
+    fs::create_dir_all(path)?;
     Ok(path.into())
 }
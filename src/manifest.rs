@@ -0,0 +1,172 @@
+use std::{collections::BTreeMap, error::Error, fs, path::Path};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::{Config, LoadTransaction},
+    cpuset,
+    paths::TestPath,
+    runtime::RuntimeKind,
+    targets::TestTarget,
+    Cli,
+};
+
+/// Describes a run, so that its results can be told apart from, and compared with, other runs'.
+///
+/// It's written to `run-manifest.json` at the root of the output directory when the run starts,
+/// and again when it finishes.
+#[derive(Debug, serde::Serialize)]
+pub struct RunManifest<'a> {
+    /// Identifies the run in every row of its results.
+    pub run_id: String,
+    pub tool_version: &'static str,
+    /// The commit the tool was built from, if it was built from a git checkout.
+    pub git_commit: Option<&'static str>,
+    pub started_at: DateTime<Utc>,
+    /// When the run finished, or `None` if it failed or hasn't finished yet.
+    pub finished_at: Option<DateTime<Utc>>,
+    pub host: HostInfo,
+    /// The version of each container runtime that could be reached, keyed by name.
+    pub container_runtimes: BTreeMap<&'static str, String>,
+    pub args: &'a Cli,
+    /// The config the run used, after command line overrides were applied.
+    pub config: &'a Config,
+    pub targets: Vec<TargetInfo>,
+    pub paths: PathsInfo<'a>,
+}
+
+impl<'a> RunManifest<'a> {
+    /// Starts a manifest for a run beginning now.
+    pub fn start(args: &'a Cli, config: &'a Config) -> Self {
+        let started_at = Utc::now();
+        RunManifest {
+            run_id: format!(
+                "{}-{}",
+                started_at.format("%Y%m%dT%H%M%SZ"),
+                std::process::id()
+            ),
+            tool_version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("GIT_COMMIT"),
+            started_at,
+            finished_at: None,
+            host: HostInfo::detect(),
+            container_runtimes: BTreeMap::new(),
+            args,
+            config,
+            targets: vec![],
+            paths: PathsInfo {
+                perf: args.perf.then_some(&config.perf.paths),
+                capacity: args.capacity.then_some(&config.capacity.paths),
+                load: args.load.then_some(&config.load.transactions),
+                warm_up: args.warm_up.then_some(&config.warm_up.paths),
+            },
+        }
+    }
+
+    pub fn write(&self, out_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(out_dir.join("run-manifest.json"), json)?;
+        Ok(())
+    }
+}
+
+/// Describes the machine a run is on.
+#[derive(Debug, serde::Serialize)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub kernel: Option<String>,
+    pub cpu_model: Option<String>,
+    /// The number of CPUs online, including any the benchmark isn't pinned to.
+    pub num_cpus: usize,
+    pub memory_bytes: Option<u64>,
+    /// The CPU frequency scaling governor, e.g. "performance" or "powersave".
+    pub cpu_governor: Option<String>,
+}
+
+impl HostInfo {
+    /// Reads what can be found out about the host. Anything unavailable is left out.
+    pub fn detect() -> Self {
+        let read = |path: &str| fs::read_to_string(path).ok().map(|s| s.trim().to_string());
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+        let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        let os_release = fs::read_to_string("/etc/os-release").unwrap_or_default();
+        HostInfo {
+            hostname: read("/proc/sys/kernel/hostname"),
+            os: find_value(&os_release, "PRETTY_NAME", '=')
+                .map(|name| name.trim_matches('"').to_string()),
+            kernel: read("/proc/sys/kernel/osrelease"),
+            cpu_model: find_value(&cpuinfo, "model name", ':').map(String::from),
+            num_cpus: cpuset::online_cpus(),
+            memory_bytes: find_value(&meminfo, "MemTotal", ':')
+                .and_then(|total| total.trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kb| kb * 1024),
+            cpu_governor: read("/sys/devices/system/cpu/cpu0/cpufreq/scaling_governor"),
+        }
+    }
+}
+
+/// Finds the value of the first line of the form "<key><separator><value>".
+fn find_value<'a>(contents: &'a str, key: &str, separator: char) -> Option<&'a str> {
+    contents.lines().find_map(|line| {
+        let (k, v) = line.split_once(separator)?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+/// Describes a target that a run benchmarks.
+#[derive(Debug, serde::Serialize)]
+pub struct TargetInfo {
+    pub name: String,
+    pub server_name: String,
+    pub runtime: RuntimeKind,
+    pub num_cpus: usize,
+    pub ram_mb: usize,
+    pub encoding: &'static str,
+    /// The image the target was run from, if it was run from one.
+    pub image: Option<String>,
+}
+
+impl From<&TestTarget<'_>> for TargetInfo {
+    fn from(target: &TestTarget) -> Self {
+        TargetInfo {
+            name: target.name(),
+            server_name: target.server_name.to_string(),
+            runtime: target.runtime,
+            num_cpus: target.num_cpus,
+            ram_mb: target.ram_mb,
+            encoding: target.encoding.as_str(),
+            image: target.image.map(|_| target.docker_target()),
+        }
+    }
+}
+
+/// The paths requested by each benchmark that a run includes.
+#[derive(Debug, serde::Serialize)]
+pub struct PathsInfo<'a> {
+    pub perf: Option<&'a [TestPath]>,
+    pub capacity: Option<&'a [TestPath]>,
+    pub load: Option<&'a [LoadTransaction]>,
+    pub warm_up: Option<&'a [TestPath]>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_value() {
+        let cpuinfo = "processor\t: 0\nmodel name\t: AMD EPYC 7R13\nmodel name\t: other\n";
+        assert_eq!(
+            find_value(cpuinfo, "model name", ':'),
+            Some("AMD EPYC 7R13")
+        );
+        assert_eq!(find_value(cpuinfo, "model", ':'), None);
+
+        let os_release = "NAME=\"Ubuntu\"\nPRETTY_NAME=\"Ubuntu 22.04.2 LTS\"\n";
+        assert_eq!(
+            find_value(os_release, "PRETTY_NAME", '='),
+            Some("\"Ubuntu 22.04.2 LTS\"")
+        );
+    }
+}
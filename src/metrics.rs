@@ -1,4 +1,5 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{self, Debug};
 use std::time::Duration;
 
 use regex::Regex;
@@ -6,26 +7,114 @@ use regex::Regex;
 #[derive(Debug, serde::Serialize)]
 pub enum Metric {
     Latency(MetricData<Duration>),
-    Qps(MetricData<f64>),
+    /// Requests completed per second by each of wrk's threads. Unlike `RequestsPerSec`, this
+    /// doesn't measure the total throughput unless there's only one thread.
+    ThreadQps(MetricData<f64>),
+    LatencyPercentile(Percentile),
+    LatencyDistribution(LatencyDistribution),
+    Totals(Totals),
+    /// The mean number of requests completed per second, across all threads. wrk reports every
+    /// response, but only 2xx and 3xx responses are counted once results are recorded.
+    RequestsPerSec(f64),
+    /// The mean number of bytes read per second, across all threads.
+    TransferPerSec(f64),
+    SocketErrors(SocketErrors),
+    /// The number of responses with a status other than 2xx or 3xx.
+    Non2xx3xxResponses(u64),
 }
 
+/// A line of wrk's output that looked like a metric, but couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: String,
+    /// What was wrong with the line.
+    pub reason: String,
+}
+
+impl ParseError {
+    fn new(line: &str, reason: String) -> Self {
+        Self {
+            line: line.to_string(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Could not parse wrk output {:?}: {}",
+            self.line, self.reason
+        )
+    }
+}
+
+impl Error for ParseError {}
+
 impl Metric {
-    pub fn try_from_wrk_output(line: &str) -> Option<Self> {
+    /// Parses a line of wrk's output, returning `None` if it doesn't hold a metric.
+    pub fn try_from_wrk_output(line: &str) -> Result<Option<Self>, ParseError> {
         lazy_static! {
             // Example output:
             // Thread Stats   Avg      Stdev     Max   +/- Stdev
             //  Latency   635.91us    0.89ms  12.92ms   93.69%
             //  Req/Sec     57.2k       4k      100k    93.69%
-            static ref LATENCY: Regex = Regex::new(r"[[:space:]]+Latency").unwrap();
-            static ref QPS: Regex = Regex::new(r"[[:space:]]+Req/Sec").unwrap();
+            // Unlike the "Latency Distribution" header, the stats are followed by a number.
+            static ref LATENCY: Regex =
+                Regex::new(r"^[[:space:]]*Latency[[:space:]]+[^[:alpha:][:space:]]").unwrap();
+            static ref QPS: Regex = Regex::new(r"^[[:space:]]*Req/Sec[[:space:]]").unwrap();
+        }
+        if let Some(percentile) = Percentile::try_from_wrk_distribution(line)? {
+            return Ok(Some(Metric::LatencyPercentile(percentile)));
         }
         if LATENCY.is_match(line) {
-            return MetricData::try_from_wrk_latency(line).map(Metric::Latency);
+            return MetricData::try_from_wrk_latency(line).map(|m| Some(Metric::Latency(m)));
         }
         if QPS.is_match(line) {
-            return MetricData::try_from_wrk_qps(line).map(Metric::Qps);
+            return MetricData::try_from_wrk_qps(line).map(|m| Some(Metric::ThreadQps(m)));
+        }
+        if let Some(totals) = Totals::try_from_wrk_totals(line)? {
+            return Ok(Some(Metric::Totals(totals)));
+        }
+        if let Some(errors) = SocketErrors::try_from_wrk_socket_errors(line)? {
+            return Ok(Some(Metric::SocketErrors(errors)));
+        }
+        // Example output:
+        //   Non-2xx or 3xx responses: 12
+        // Requests/sec:  57219.10
+        // Transfer/sec:      6.76MB
+        if let Some(value) = find_labelled_value(line, "Non-2xx or 3xx responses:") {
+            return parse_metric(line, str_into_integer(value), Metric::Non2xx3xxResponses);
+        }
+        if let Some(value) = find_labelled_value(line, "Requests/sec:") {
+            return parse_metric(line, str_into_count(value), Metric::RequestsPerSec);
+        }
+        if let Some(value) = find_labelled_value(line, "Transfer/sec:") {
+            return parse_metric(line, str_into_bytes(value), Metric::TransferPerSec);
         }
-        None
+        Ok(None)
+    }
+
+    /// Parses every metric in wrk's full output.
+    ///
+    /// The percentiles of wrk2's uncorrected latency, which it prints with `-U` after those of the
+    /// corrected latency, are skipped, to match the latency parsed elsewhere.
+    pub fn try_all_from_wrk_output(out: &str) -> Result<Vec<Self>, ParseError> {
+        let mut metrics = vec![];
+        let mut is_uncorrected = false;
+        for line in out.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("Latency Distribution") {
+                is_uncorrected = trimmed.contains("Uncorrected");
+            }
+            match Metric::try_from_wrk_output(line)? {
+                Some(Metric::LatencyPercentile(_)) if is_uncorrected => {}
+                Some(metric) => metrics.push(metric),
+                None => {}
+            }
+        }
+        Ok(metrics)
     }
 }
 
@@ -37,7 +126,17 @@ pub struct MetricData<T: Debug> {
 }
 
 impl MetricData<Duration> {
-    pub fn try_from_wrk_latency(latency_line: &str) -> Option<Self> {
+    /// Summarizes a set of latency samples, or returns `None` if there are none.
+    pub fn from_latencies(latencies: &[Duration]) -> Option<Self> {
+        let secs: Vec<f64> = latencies.iter().map(Duration::as_secs_f64).collect();
+        MetricData::from_samples(&secs).map(|data| Self {
+            mean: Duration::from_secs_f64(data.mean),
+            std_dev: Duration::from_secs_f64(data.std_dev),
+            max: Duration::from_secs_f64(data.max),
+        })
+    }
+
+    pub fn try_from_wrk_latency(latency_line: &str) -> Result<Self, ParseError> {
         /// Matches (and captures) a time value that is in either us, ns, ms, or seconds.
         const TIME_REGEX: &str = r"([[:digit:]]+(?:\.[[:digit:]]+)?[mun]?s)";
         lazy_static! {
@@ -48,16 +147,38 @@ impl MetricData<Duration> {
                 &format!(r"[[:space:]]*Latency[[:space:]]+{}[[:space:]]+{}[[:space:]]+{}", TIME_REGEX, TIME_REGEX, TIME_REGEX)).unwrap();
         }
 
-        ALL_TIMES.captures(latency_line).map(|captures| Self {
-            mean: time_str_into_duration(captures.get(1).unwrap().as_str()),
-            std_dev: time_str_into_duration(captures.get(2).unwrap().as_str()),
-            max: time_str_into_duration(captures.get(3).unwrap().as_str()),
+        let captures = ALL_TIMES
+            .captures(latency_line)
+            .ok_or_else(|| ParseError::new(latency_line, String::from("expected 3 latencies")))?;
+        let time = |i: usize| {
+            time_str_into_duration(&captures[i])
+                .map_err(|reason| ParseError::new(latency_line, reason))
+        };
+        Ok(Self {
+            mean: time(1)?,
+            std_dev: time(2)?,
+            max: time(3)?,
         })
     }
 }
 
 impl MetricData<f64> {
-    pub fn try_from_wrk_qps(qps_line: &str) -> Option<Self> {
+    /// Summarizes a set of samples, or returns `None` if there are none.
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / count;
+        Some(Self {
+            mean,
+            std_dev: variance.sqrt(),
+            max: samples.iter().cloned().fold(f64::MIN, f64::max),
+        })
+    }
+
+    pub fn try_from_wrk_qps(qps_line: &str) -> Result<Self, ParseError> {
         /// Matches (and captures) a time value that is in either us, ns, ms, or seconds.
         const COUNT_REGEX: &str = r"([[:digit:]]+(?:\.[[:digit:]]+)?[mkMG]?)";
         lazy_static! {
@@ -68,70 +189,326 @@ impl MetricData<f64> {
                 &format!(r"[[:space:]]*Req/Sec[[:space:]]+{}[[:space:]]+{}[[:space:]]+{}", COUNT_REGEX, COUNT_REGEX, COUNT_REGEX)).unwrap();
         }
 
-        ALL_COUNTS.captures(qps_line).map(|captures| Self {
-            mean: str_into_count(captures.get(1).unwrap().as_str()),
-            std_dev: str_into_count(captures.get(2).unwrap().as_str()),
-            max: str_into_count(captures.get(3).unwrap().as_str()),
+        let captures = ALL_COUNTS
+            .captures(qps_line)
+            .ok_or_else(|| ParseError::new(qps_line, String::from("expected 3 counts")))?;
+        let count = |i: usize| {
+            str_into_count(&captures[i]).map_err(|reason| ParseError::new(qps_line, reason))
+        };
+        Ok(Self {
+            mean: count(1)?,
+            std_dev: count(2)?,
+            max: count(3)?,
         })
     }
 }
 
-fn time_str_into_duration(time_str: &str) -> Duration {
-    lazy_static! {
-        static ref NS: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)ns").unwrap();
-        static ref US: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)us").unwrap();
-        static ref MS: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)ms").unwrap();
-        static ref S: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)s").unwrap();
+/// Gets the rest of a line that starts with `label`, ignoring surrounding whitespace.
+fn find_labelled_value<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+    Some(line.trim().strip_prefix(label)?.trim())
+}
+
+fn parse_metric<T>(
+    line: &str,
+    value: Result<T, String>,
+    metric: impl FnOnce(T) -> Metric,
+) -> Result<Option<Metric>, ParseError> {
+    value
+        .map(|value| Some(metric(value)))
+        .map_err(|reason| ParseError::new(line, reason))
+}
+
+/// What was sent and received over a whole wrk run.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub duration: Duration,
+    pub bytes_read: f64,
+}
+
+impl Totals {
+    /// Parses wrk's summary line, returning `None` if the line isn't one.
+    pub fn try_from_wrk_totals(line: &str) -> Result<Option<Self>, ParseError> {
+        lazy_static! {
+            // Example output:
+            //   22464657 requests in 30.00s, 17.76GB read
+            static ref TOTALS: Regex = Regex::new(
+                r"^[[:space:]]*([^[:space:]]+) requests in ([^[:space:]]+), ([^[:space:]]+) read[[:space:]]*$").unwrap();
+        }
+
+        let Some(captures) = TOTALS.captures(line) else {
+            return Ok(None);
+        };
+        let error = |reason| ParseError::new(line, reason);
+        Ok(Some(Self {
+            requests: str_into_integer(&captures[1]).map_err(error)?,
+            duration: time_str_into_duration(&captures[2]).map_err(error)?,
+            bytes_read: str_into_bytes(&captures[3]).map_err(error)?,
+        }))
+    }
+}
+
+/// Requests that wrk couldn't complete, by the stage at which they failed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct SocketErrors {
+    pub connect: u64,
+    pub read: u64,
+    pub write: u64,
+    pub timeout: u64,
+}
+
+impl SocketErrors {
+    /// Parses wrk's socket errors line, returning `None` if the line isn't one.
+    pub fn try_from_wrk_socket_errors(line: &str) -> Result<Option<Self>, ParseError> {
+        lazy_static! {
+            // Example output:
+            //   Socket errors: connect 0, read 12, write 0, timeout 3
+            static ref SOCKET_ERRORS: Regex = Regex::new(
+                r"^[[:space:]]*Socket errors: connect ([^,]+), read ([^,]+), write ([^,]+), timeout ([^[:space:]]+)[[:space:]]*$").unwrap();
+        }
+
+        let Some(value) = find_labelled_value(line, "Socket errors:") else {
+            return Ok(None);
+        };
+        let captures = SOCKET_ERRORS.captures(line).ok_or_else(|| {
+            ParseError::new(
+                line,
+                format!(
+                    "expected connect, read, write and timeout errors in {:?}",
+                    value
+                ),
+            )
+        })?;
+        let count = |i: usize| {
+            str_into_integer(&captures[i]).map_err(|reason| ParseError::new(line, reason))
+        };
+        Ok(Some(Self {
+            connect: count(1)?,
+            read: count(2)?,
+            write: count(3)?,
+            timeout: count(4)?,
+        }))
     }
+}
 
-    NS.captures(time_str)
-        .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap())
-        .or_else(|| {
-            US.captures(time_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 1000.0)
-        })
-        .or_else(|| {
-            MS.captures(time_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 1000_000.0)
-        })
-        .or_else(|| {
-            S.captures(time_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 1000_000_000.0)
-        })
-        .map(|ns| Duration::from_nanos(ns.round() as u64))
-        .expect(&format!("Could not parse time {}", time_str))
+/// The latency below which a given percentage of requests completed.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Percentile {
+    /// The percentile, from 0 to 100.
+    pub percentile: f64,
+    pub latency: Duration,
 }
 
-fn str_into_count(count_str: &str) -> f64 {
-    lazy_static! {
-        static ref MILLI: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)m").unwrap();
-        static ref PLAIN: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)$").unwrap();
-        static ref KILO: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)k").unwrap();
-        static ref MEGA: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)M").unwrap();
-        static ref GIGA: Regex = Regex::new(r"([[:digit:]]+(?:\.[[:digit:]]+)?)G").unwrap();
+/// The percentiles that are reported for latency distributions.
+pub const REPORTED_PERCENTILES: [f64; 5] = [50.0, 75.0, 90.0, 99.0, 99.9];
+
+/// The percentiles in a full latency distribution, matching those in wrk2's summary.
+pub const DISTRIBUTION_PERCENTILES: [f64; 8] = [50.0, 75.0, 90.0, 99.0, 99.9, 99.99, 99.999, 100.0];
+
+impl Percentile {
+    /// Parses a line of a latency distribution, returning `None` if the line isn't one.
+    pub fn try_from_wrk_distribution(line: &str) -> Result<Option<Self>, ParseError> {
+        lazy_static! {
+            // Example output (from `wrk --latency`):
+            //   Latency Distribution
+            //      50%  635.00us
+            //      99%    1.20ms
+            static ref PERCENTILE: Regex = Regex::new(
+                r"^[[:space:]]*([[:digit:]]+(?:\.[[:digit:]]+)?)%[[:space:]]+([[:digit:]]+(?:\.[[:digit:]]+)?[mun]?s)[[:space:]]*$").unwrap();
+        }
+
+        let Some(captures) = PERCENTILE.captures(line) else {
+            return Ok(None);
+        };
+        let percentile = captures[1]
+            .parse()
+            .map_err(|_| ParseError::new(line, format!("invalid percentile {:?}", &captures[1])))?;
+        let latency =
+            time_str_into_duration(&captures[2]).map_err(|reason| ParseError::new(line, reason))?;
+        Ok(Some(Self {
+            percentile,
+            latency,
+        }))
     }
 
-    MILLI
-        .captures(count_str)
-        .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 0.001)
-        .or_else(|| {
-            PLAIN
-                .captures(count_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap())
-        })
-        .or_else(|| {
-            KILO.captures(count_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 1000.0)
-        })
-        .or_else(|| {
-            MEGA.captures(count_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 1000_000.0)
-        })
-        .or_else(|| {
-            GIGA.captures(count_str)
-                .map(|c| c.get(1).unwrap().as_str().parse::<f64>().unwrap() * 1000_000_000.0)
-        })
-        .expect(&format!("Could not parse count {}", count_str))
+    /// Parses a line of wrk2's detailed percentile spectrum, returning `None` if the line isn't
+    /// one.
+    pub fn try_from_wrk_spectrum(line: &str) -> Result<Option<Self>, ParseError> {
+        lazy_static! {
+            // Example output (from wrk2 with `--latency`), where values are in ms:
+            //        Value   Percentile   TotalCount 1/(1-Percentile)
+            //
+            //        0.921 0.000000000000            1           1.00
+            //        1.217 0.100000000000        10010           1.11
+            static ref SPECTRUM: Regex = Regex::new(
+                r"^[[:space:]]*([^[:space:]]+)[[:space:]]+([[:digit:]]\.[[:digit:]]+)[[:space:]]+[[:digit:]]+[[:space:]]+[^[:space:]]+[[:space:]]*$").unwrap();
+        }
+
+        let Some(captures) = SPECTRUM.captures(line) else {
+            return Ok(None);
+        };
+        let latency_ms: f64 = captures[1]
+            .parse()
+            .ok()
+            .filter(|ms: &f64| ms.is_finite() && *ms >= 0.0)
+            .ok_or_else(|| ParseError::new(line, format!("invalid latency {:?}", &captures[1])))?;
+        let percentile: f64 = captures[2]
+            .parse()
+            .map_err(|_| ParseError::new(line, format!("invalid percentile {:?}", &captures[2])))?;
+        Ok(Some(Self {
+            percentile: percentile * 100.0,
+            latency: Duration::from_secs_f64(latency_ms / 1000.0),
+        }))
+    }
+
+    /// Computes the nearest-rank percentiles of the given latencies.
+    pub fn from_latencies(latencies: &[Duration], percentiles: &[f64]) -> Vec<Self> {
+        if latencies.is_empty() {
+            return vec![];
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        percentiles
+            .iter()
+            .map(|&percentile| {
+                // Allow for floating point error so that e.g. p99.9 of 1000 samples is rank 999.
+                let rank = (percentile / 100.0 * sorted.len() as f64 - 1e-9).ceil() as usize;
+                Self {
+                    percentile,
+                    latency: sorted[rank.clamp(1, sorted.len()) - 1],
+                }
+            })
+            .collect()
+    }
+}
+
+/// The latency at each of a range of percentiles.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LatencyDistribution {
+    /// The percentiles, in increasing order.
+    pub percentiles: Vec<Percentile>,
+}
+
+/// The block of wrk's output that a line is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WrkBlock {
+    Other,
+    Distribution,
+    Spectrum,
+    /// Either block for wrk2's uncorrected latency, which is printed with `-U`.
+    Uncorrected,
+}
+
+impl LatencyDistribution {
+    /// Parses the latency distribution from wrk's full output, returning `None` if it didn't
+    /// print one.
+    ///
+    /// wrk2's detailed percentile spectrum is used if it was printed, or else the "Latency
+    /// Distribution" block. wrk2's uncorrected latency is ignored, to match the latency parsed
+    /// elsewhere.
+    pub fn try_from_wrk_output(out: &str) -> Result<Option<Self>, ParseError> {
+        let mut distribution = vec![];
+        let mut spectrum = vec![];
+        let mut block = WrkBlock::Other;
+        for line in out.lines() {
+            let trimmed = line.trim();
+            // Example output:
+            //   Latency Distribution (HdrHistogram - Recorded Latency)
+            //   ...
+            //   Detailed Percentile spectrum:
+            if trimmed.starts_with("Latency Distribution") {
+                block = match trimmed.contains("Uncorrected") {
+                    true => WrkBlock::Uncorrected,
+                    false => WrkBlock::Distribution,
+                };
+                continue;
+            }
+            if trimmed.starts_with("Detailed Percentile spectrum") {
+                if block != WrkBlock::Uncorrected {
+                    block = WrkBlock::Spectrum;
+                }
+                continue;
+            }
+            match block {
+                WrkBlock::Distribution => match Percentile::try_from_wrk_distribution(line)? {
+                    Some(percentile) => distribution.push(percentile),
+                    None => block = WrkBlock::Other,
+                },
+                WrkBlock::Spectrum if trimmed.is_empty() || trimmed.starts_with("Value") => {}
+                WrkBlock::Spectrum => match Percentile::try_from_wrk_spectrum(line)? {
+                    Some(percentile) => spectrum.push(percentile),
+                    None => block = WrkBlock::Other,
+                },
+                WrkBlock::Other | WrkBlock::Uncorrected => {}
+            }
+        }
+        let percentiles = match spectrum.is_empty() {
+            true => distribution,
+            false => spectrum,
+        };
+        Ok((!percentiles.is_empty()).then_some(Self { percentiles }))
+    }
+}
+
+/// Parses a number with an optional unit suffix, e.g. "1.5ms", scaling it by the suffix's
+/// multiplier. The longest matching suffix is used.
+fn parse_with_unit(value: &str, units: &[(&str, f64)]) -> Option<f64> {
+    let (number, multiplier) = units
+        .iter()
+        .filter_map(|(unit, multiplier)| Some((value.strip_suffix(unit)?, *multiplier)))
+        .min_by_key(|(number, _)| number.len())?;
+    let number: f64 = number.parse().ok()?;
+    number.is_finite().then_some(number * multiplier)
+}
+
+/// Parses a time value in ns, us, ms, s, m or h.
+fn time_str_into_duration(time_str: &str) -> Result<Duration, String> {
+    const UNITS: [(&str, f64); 6] = [
+        ("ns", 1.0),
+        ("us", 1_000.0),
+        ("ms", 1_000_000.0),
+        ("s", 1_000_000_000.0),
+        ("m", 60_000_000_000.0),
+        ("h", 3_600_000_000_000.0),
+    ];
+    parse_with_unit(time_str, &UNITS)
+        .filter(|ns| *ns >= 0.0)
+        .map(|ns| Duration::from_nanos(ns.round() as u64))
+        .ok_or_else(|| format!("invalid time {:?}", time_str))
+}
+
+/// Parses a whole number without a suffix.
+fn str_into_integer(integer_str: &str) -> Result<u64, String> {
+    integer_str
+        .parse()
+        .map_err(|_| format!("invalid integer {:?}", integer_str))
+}
+
+/// Parses a number of bytes with a B, KB, MB, GB, TB or PB suffix. Like wrk, a KB is 1024 bytes.
+fn str_into_bytes(bytes_str: &str) -> Result<f64, String> {
+    const UNITS: [(&str, f64); 6] = [
+        ("B", 1.0),
+        ("KB", 1024.0),
+        ("MB", 1024.0 * 1024.0),
+        ("GB", 1024.0 * 1024.0 * 1024.0),
+        ("TB", 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("PB", 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0),
+    ];
+    parse_with_unit(bytes_str, &UNITS)
+        .filter(|bytes| *bytes >= 0.0)
+        .ok_or_else(|| format!("invalid size {:?}", bytes_str))
+}
+
+/// Parses a count with an optional m, k, M or G suffix.
+fn str_into_count(count_str: &str) -> Result<f64, String> {
+    const UNITS: [(&str, f64); 5] = [
+        ("", 1.0),
+        ("m", 0.001),
+        ("k", 1000.0),
+        ("M", 1_000_000.0),
+        ("G", 1_000_000_000.0),
+    ];
+    parse_with_unit(count_str, &UNITS).ok_or_else(|| format!("invalid count {:?}", count_str))
 }
 
 #[cfg(test)]
@@ -147,7 +524,12 @@ mod test {
             ("123.4us", Duration::from_nanos(123_400)),
             ("123.4s", Duration::from_millis(123_400)),
         ]
-        .map(|(time_str, expected)| assert_eq!(time_str_into_duration(time_str), expected));
+        .iter()
+        .for_each(|(time_str, expected)| {
+            assert_eq!(time_str_into_duration(time_str), Ok(*expected))
+        });
+        assert!(time_str_into_duration("-nanus").is_err());
+        assert!(time_str_into_duration("12").is_err());
     }
 
     #[test]
@@ -160,14 +542,37 @@ mod test {
             ("123.4M", 123_400_000.0),
             ("123.4G", 123_400_000_000.0),
         ]
-        .map(|(count_str, expected)| assert_relative_eq!(str_into_count(count_str), expected));
+        .iter()
+        .for_each(|(count_str, expected)| {
+            assert_relative_eq!(str_into_count(count_str).unwrap(), *expected)
+        });
+        assert!(str_into_count("-nan").is_err());
+        assert!(str_into_count("1.2x").is_err());
+    }
+
+    #[test]
+    fn test_from_samples() {
+        assert!(MetricData::from_samples(&[]).is_none());
+        let m = MetricData::from_samples(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+        assert_relative_eq!(m.mean, 5.0);
+        assert_relative_eq!(m.std_dev, 2.0);
+        assert_relative_eq!(m.max, 9.0);
+    }
+
+    #[test]
+    fn test_from_latencies() {
+        let m = MetricData::from_latencies(&[Duration::from_millis(1), Duration::from_millis(3)])
+            .unwrap();
+        assert_eq!(m.mean, Duration::from_millis(2));
+        assert_eq!(m.std_dev, Duration::from_millis(1));
+        assert_eq!(m.max, Duration::from_millis(3));
     }
 
     #[test]
     fn test_try_from_wrk_latency() {
         let m =
             MetricData::try_from_wrk_latency("    Latency   441.23ms   58.18us   2.63s   91.22%");
-        assert_eq!(m.is_some(), true);
+        assert!(m.is_ok());
         let m = m.unwrap();
         assert_eq!(
             m.mean,
@@ -183,13 +588,194 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_try_from_wrk_distribution() {
+        let p = Percentile::try_from_wrk_distribution("     99%    1.20ms")
+            .unwrap()
+            .unwrap();
+        assert_relative_eq!(p.percentile, 99.0);
+        assert_eq!(p.latency, Duration::from_micros(1200));
+        assert!(matches!(
+            Percentile::try_from_wrk_distribution(
+                "    Latency   441.23ms   58.18us   2.63s   91.22%"
+            ),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn test_latency_distribution_from_wrk() {
+        let wrk = "\
+  Latency Distribution
+     50%  635.00us
+     99%    1.20ms
+Requests/sec:  57219.10
+";
+        let d = LatencyDistribution::try_from_wrk_output(wrk)
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.percentiles.len(), 2);
+        assert_eq!(d.percentiles[1].latency, Duration::from_micros(1200));
+
+        let wrk2 = "\
+  Latency Distribution (HdrHistogram - Recorded Latency)
+ 50.000%    1.14ms
+100.000%   11.21ms
+
+  Detailed Percentile spectrum:
+       Value   Percentile   TotalCount 1/(1-Percentile)
+
+       0.921 0.000000000000            1           1.00
+       1.217 0.100000000000        10010           1.11
+      11.208 1.000000000000       100000            inf
+#[Mean    =        1.223, StdDeviation   =        0.519]
+----------------------------------------------------------
+  Latency Distribution (HdrHistogram - Uncorrected Latency (measured without taking delayed starts into account))
+ 50.000%  500.00us
+
+  Detailed Percentile spectrum:
+       Value   Percentile   TotalCount 1/(1-Percentile)
+
+       0.100 0.000000000000            1           1.00
+";
+        let d = LatencyDistribution::try_from_wrk_output(wrk2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.percentiles.len(), 3);
+        assert_relative_eq!(d.percentiles[1].percentile, 10.0);
+        assert_eq!(d.percentiles[1].latency, Duration::from_micros(1217));
+        assert_relative_eq!(d.percentiles[2].percentile, 100.0);
+
+        assert!(matches!(
+            LatencyDistribution::try_from_wrk_output("Requests/sec:  57219.10\n"),
+            Ok(None)
+        ));
+        assert!(LatencyDistribution::try_from_wrk_output(
+            "  Detailed Percentile spectrum:\n       -nan 0.100000000000            1           1.11\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_percentiles_from_latencies() {
+        let latencies: Vec<Duration> = (1..=1000).rev().map(Duration::from_millis).collect();
+        let p = Percentile::from_latencies(&latencies, &[50.0, 99.9, 100.0]);
+        assert_eq!(p[0].latency, Duration::from_millis(500));
+        assert_eq!(p[1].latency, Duration::from_millis(999));
+        assert_eq!(p[2].latency, Duration::from_millis(1000));
+        assert!(Percentile::from_latencies(&[], &[50.0]).is_empty());
+    }
+
     #[test]
     fn test_try_from_wrk_qps() {
         let m = MetricData::try_from_wrk_qps("    Req/Sec   441.23m   58.18   2.63k   91.22%");
-        assert_eq!(m.is_some(), true);
+        assert!(m.is_ok());
         let m = m.unwrap();
         assert_relative_eq!(m.mean, 441.23f64 * 0.001);
         assert_relative_eq!(m.std_dev, 58.18f64);
         assert_relative_eq!(m.max, 2.63f64 * 1000.0);
     }
+
+    #[test]
+    fn test_try_from_wrk_report() {
+        let report = "\
+Running 30s test @ http://localhost:8080/
+  1 threads and 100 connections
+  Thread Stats   Avg      Stdev     Max   +/- Stdev
+    Latency   635.91us    0.89ms  12.92ms   93.69%
+    Req/Sec    56.20k     8.07k   62.00k    86.54%
+  Latency Distribution
+     50%  512.00us
+     99%    4.10ms
+  1686000 requests in 30.00s, 1.33GB read
+  Socket errors: connect 0, read 12, write 0, timeout 3
+  Non-2xx or 3xx responses: 1686000
+Requests/sec:  56200.00
+Transfer/sec:     45.50MB
+";
+        let metrics: Vec<Metric> = report
+            .lines()
+            .filter_map(|line| Metric::try_from_wrk_output(line).unwrap())
+            .collect();
+        assert_eq!(metrics.len(), 9);
+        assert!(matches!(
+            &metrics[4],
+            Metric::Totals(Totals { requests: 1686000, duration, bytes_read })
+                if *duration == Duration::from_secs(30)
+                    && (bytes_read - 1.33 * 1024.0 * 1024.0 * 1024.0).abs() < 1.0
+        ));
+        assert!(matches!(
+            &metrics[5],
+            Metric::SocketErrors(SocketErrors {
+                connect: 0,
+                read: 12,
+                write: 0,
+                timeout: 3
+            })
+        ));
+        assert!(matches!(metrics[6], Metric::Non2xx3xxResponses(1686000)));
+        assert!(matches!(metrics[7], Metric::RequestsPerSec(qps) if qps == 56200.0));
+        assert!(
+            matches!(metrics[8], Metric::TransferPerSec(bytes) if bytes == 45.5 * 1024.0 * 1024.0)
+        );
+
+        assert!(Metric::try_from_wrk_output("  1 requests in 1.50m, 0.00B read").is_ok());
+        assert!(Metric::try_from_wrk_output("  Socket errors: connect 0").is_err());
+        assert!(Metric::try_from_wrk_output("Transfer/sec: lots").is_err());
+    }
+
+    #[test]
+    fn test_try_all_from_wrk2_report() {
+        let report = "\
+  Latency Distribution (HdrHistogram - Recorded Latency)
+ 50.000%    1.14ms
+ 99.000%    4.10ms
+
+  Detailed Percentile spectrum:
+       Value   Percentile   TotalCount 1/(1-Percentile)
+
+       1.140 0.500000000000        50000           2.00
+#[Mean    =        1.223, StdDeviation   =        0.519]
+----------------------------------------------------------
+  Latency Distribution (HdrHistogram - Uncorrected Latency (measured without taking delayed starts into account))
+ 50.000%  500.00us
+ 99.000%    1.20ms
+
+  Detailed Percentile spectrum:
+       Value   Percentile   TotalCount 1/(1-Percentile)
+
+       0.500 0.500000000000        50000           2.00
+----------------------------------------------------------
+  100000 requests in 10.00s, 45.50MB read
+Requests/sec:  10000.00
+";
+        let metrics = Metric::try_all_from_wrk_output(report).unwrap();
+        let percentiles: Vec<&Percentile> = metrics
+            .iter()
+            .filter_map(|m| match m {
+                Metric::LatencyPercentile(p) => Some(p),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(percentiles.len(), 2);
+        assert_relative_eq!(percentiles[0].percentile, 50.0);
+        assert_eq!(percentiles[0].latency, Duration::from_micros(1140));
+        assert_eq!(percentiles[1].latency, Duration::from_micros(4100));
+        assert!(metrics.iter().any(|m| matches!(m, Metric::Totals(_))));
+        assert!(metrics
+            .iter()
+            .any(|m| matches!(m, Metric::RequestsPerSec(qps) if *qps == 10000.0)));
+    }
+
+    #[test]
+    fn test_try_from_wrk_output_errors() {
+        let line = "    Latency    -nanus    -nanus   0.00us    -nan%";
+        let e = Metric::try_from_wrk_output(line).unwrap_err();
+        assert_eq!(e.line, line);
+        assert!(Metric::try_from_wrk_output("    Req/Sec     0.00      0.00     0.00").is_ok());
+        assert!(matches!(
+            Metric::try_from_wrk_output("Running 10s test @ http://localhost:8080/"),
+            Ok(None)
+        ));
+    }
 }
@@ -0,0 +1,265 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinSet;
+
+use crate::{
+    metrics::{
+        LatencyDistribution, Metric, MetricData, Percentile, SocketErrors,
+        DISTRIBUTION_PERCENTILES, REPORTED_PERCENTILES,
+    },
+    targets::TestTarget,
+};
+
+/// Timing data for a single request issued by the native engine.
+#[derive(Debug, Clone)]
+pub struct RequestTiming {
+    /// When the request was sent, relative to the start of the run. In open-loop runs, this is
+    /// when the request was scheduled to be sent.
+    pub sent_at: Duration,
+    /// How long it took to receive the full response, measured from `sent_at`.
+    pub latency: Duration,
+    pub outcome: Outcome,
+}
+
+impl RequestTiming {
+    /// Whether a complete response with a 2xx or 3xx status was received, like wrk counts.
+    pub fn is_success(&self) -> bool {
+        matches!(self.outcome, Outcome::Response(status) if (200..400).contains(&status))
+    }
+}
+
+/// How a request ended, with the same kinds of failure that wrk reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// A complete response was received with this status.
+    Response(u16),
+    ConnectError,
+    /// The request couldn't be sent.
+    WriteError,
+    /// The response couldn't be read.
+    ReadError,
+    Timeout,
+}
+
+impl Outcome {
+    fn from_error(e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Outcome::Timeout
+        } else if e.is_connect() {
+            Outcome::ConnectError
+        } else if e.is_request() {
+            Outcome::WriteError
+        } else {
+            Outcome::ReadError
+        }
+    }
+}
+
+/// Builds a client that negotiates compression according to the target.
+pub fn client_for(target: &TestTarget, connections: usize) -> reqwest::Result<reqwest::Client> {
+    let client_builder = reqwest::Client::builder()
+        .pool_max_idle_per_host(connections)
+        .timeout(Duration::from_secs(10));
+    target.encoding.configure_client(client_builder).build()
+}
+
+async fn timed_get(client: &reqwest::Client, url: &str) -> Outcome {
+    match client.get(url).send().await {
+        Ok(resp) => {
+            let status = resp.status().as_u16();
+            match resp.bytes().await {
+                Ok(_) => Outcome::Response(status),
+                Err(e) => Outcome::from_error(&e),
+            }
+        }
+        Err(e) => Outcome::from_error(&e),
+    }
+}
+
+/// Sends requests to `url` from `connections` concurrent workers for `duration`.
+///
+/// Each worker sends its next request as soon as it has read the previous response, like wrk.
+/// Workers are spawned as their own tasks, sharing the client's connection pool, so that they run
+/// across all of the runtime's threads.
+pub async fn run_closed_loop(
+    client: &reqwest::Client,
+    url: &str,
+    connections: usize,
+    duration: Duration,
+) -> Vec<RequestTiming> {
+    let run_start = Instant::now();
+    let url: Arc<str> = Arc::from(url);
+    let workers: Vec<_> = (0..connections)
+        .map(|_| {
+            let (client, url) = (client.clone(), url.clone());
+            tokio::spawn(async move {
+                let mut timings = vec![];
+                while run_start.elapsed() < duration {
+                    let req_time = Instant::now();
+                    let outcome = timed_get(&client, &url).await;
+                    timings.push(RequestTiming {
+                        sent_at: req_time.duration_since(run_start),
+                        latency: req_time.elapsed(),
+                        outcome,
+                    });
+                }
+                timings
+            })
+        })
+        .collect();
+    let mut timings = vec![];
+    for worker in workers {
+        timings.extend(worker.await.unwrap_or_default());
+    }
+    timings.sort_by_key(|t| t.sent_at);
+    timings
+}
+
+/// Sends requests to `url` at a constant `rate` per second for `duration`.
+///
+/// Requests are sent on schedule regardless of how long earlier responses take, and latency is
+/// measured from when each request should have been sent. This avoids coordinated omission, where
+/// a slow server would otherwise reduce the load it is tested under.
+///
+/// Each request is spawned as its own task when it's due, and collected once it finishes, so that
+/// only the requests in flight are held at once.
+pub async fn run_open_loop(
+    client: &reqwest::Client,
+    url: &str,
+    rate: f64,
+    duration: Duration,
+) -> Vec<RequestTiming> {
+    let run_start = tokio::time::Instant::now();
+    let num_requests = (rate * duration.as_secs_f64()).round() as usize;
+    let url: Arc<str> = Arc::from(url);
+    let mut in_flight = JoinSet::new();
+    let mut timings = Vec::with_capacity(num_requests);
+    for i in 0..num_requests {
+        let sent_at = Duration::from_secs_f64(i as f64 / rate);
+        tokio::time::sleep_until(run_start + sent_at).await;
+        let (client, url) = (client.clone(), url.clone());
+        in_flight.spawn(async move {
+            let outcome = timed_get(&client, &url).await;
+            RequestTiming {
+                sent_at,
+                latency: run_start.elapsed().saturating_sub(sent_at),
+                outcome,
+            }
+        });
+        while let Some(timing) = in_flight.try_join_next() {
+            timings.extend(timing.ok());
+        }
+    }
+    while let Some(timing) = in_flight.join_next().await {
+        timings.extend(timing.ok());
+    }
+    timings.sort_by_key(|t| t.sent_at);
+    timings
+}
+
+/// Gets how long a run took, from its start until its last request finished.
+pub fn run_duration(timings: &[RequestTiming]) -> Duration {
+    timings
+        .iter()
+        .map(|t| t.sent_at + t.latency)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Summarizes request timings into the same metrics that are parsed from wrk's output.
+///
+/// Latency and throughput only include successful requests, so that fast failures don't make a
+/// server look faster. Failures are counted separately, as socket errors and non-2xx or 3xx
+/// responses. Throughput is measured over the whole run, up to when the last request finished. The
+/// native engine has no threads of its own, so no per-thread throughput is recorded.
+pub fn metrics_from_timings(timings: &[RequestTiming]) -> Vec<Metric> {
+    let mut metrics = vec![];
+    let latencies: Vec<Duration> = timings
+        .iter()
+        .filter(|t| t.is_success())
+        .map(|t| t.latency)
+        .collect();
+    if let Some(data) = MetricData::from_latencies(&latencies) {
+        metrics.push(Metric::Latency(data));
+    }
+    metrics.extend(
+        Percentile::from_latencies(&latencies, &REPORTED_PERCENTILES)
+            .into_iter()
+            .map(Metric::LatencyPercentile),
+    );
+    let percentiles = Percentile::from_latencies(&latencies, &DISTRIBUTION_PERCENTILES);
+    if !percentiles.is_empty() {
+        metrics.push(Metric::LatencyDistribution(LatencyDistribution {
+            percentiles,
+        }));
+    }
+
+    let run_secs = run_duration(timings).as_secs_f64();
+    if run_secs > 0.0 && !latencies.is_empty() {
+        metrics.push(Metric::RequestsPerSec(latencies.len() as f64 / run_secs));
+    }
+
+    let mut socket_errors = SocketErrors::default();
+    let mut non_2xx_3xx_responses = 0;
+    for timing in timings {
+        match timing.outcome {
+            Outcome::Response(_) if timing.is_success() => {}
+            Outcome::Response(_) => non_2xx_3xx_responses += 1,
+            Outcome::ConnectError => socket_errors.connect += 1,
+            Outcome::WriteError => socket_errors.write += 1,
+            Outcome::ReadError => socket_errors.read += 1,
+            Outcome::Timeout => socket_errors.timeout += 1,
+        }
+    }
+    metrics.push(Metric::SocketErrors(socket_errors));
+    metrics.push(Metric::Non2xx3xxResponses(non_2xx_3xx_responses));
+    metrics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn timing(sent_at_ms: u64, latency_ms: u64, outcome: Outcome) -> RequestTiming {
+        RequestTiming {
+            sent_at: Duration::from_millis(sent_at_ms),
+            latency: Duration::from_millis(latency_ms),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_metrics_from_timings_exclude_failures() {
+        let timings = [
+            timing(0, 10, Outcome::Response(200)),
+            timing(500, 30, Outcome::Response(200)),
+            timing(600, 1, Outcome::Response(500)),
+            timing(700, 1300, Outcome::Timeout),
+            timing(800, 1, Outcome::ConnectError),
+        ];
+        let metrics = metrics_from_timings(&timings);
+        assert!(metrics.iter().any(|m| matches!(
+            m,
+            Metric::Latency(data) if data.mean == Duration::from_millis(20)
+                && data.max == Duration::from_millis(30)
+        )));
+        assert!(metrics
+            .iter()
+            .any(|m| matches!(m, Metric::RequestsPerSec(qps) if (qps - 1.0).abs() < 1e-9)));
+        assert!(metrics.iter().any(|m| matches!(
+            m,
+            Metric::SocketErrors(SocketErrors {
+                connect: 1,
+                read: 0,
+                write: 0,
+                timeout: 1
+            })
+        )));
+        assert!(metrics
+            .iter()
+            .any(|m| matches!(m, Metric::Non2xx3xxResponses(1))));
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    error::Error,
+    fs, io,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{config::NoiseConfig, cpuset, targets::TestTarget};
+
+/// How long to measure the host's CPU usage for before each run, while the server is idle.
+const BASELINE_DURATION: Duration = Duration::from_secs(1);
+/// How often to sample the host during a run.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Cumulative CPU time across all of the host's CPUs, in clock ticks, as read from /proc/stat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CpuTimes {
+    busy: u64,
+    idle: u64,
+    steal: u64,
+}
+
+impl CpuTimes {
+    fn read() -> io::Result<Self> {
+        let stat = fs::read_to_string("/proc/stat")?;
+        Self::parse(&stat).ok_or_else(|| io::Error::other("Unexpected /proc/stat format"))
+    }
+
+    /// Parses the aggregate "cpu" line.
+    fn parse(stat: &str) -> Option<Self> {
+        Self::parse_fields(stat.lines().find_map(|line| line.strip_prefix("cpu "))?)
+    }
+
+    /// Parses the per-CPU "cpuN" lines of the CPUs that `include` accepts, and sums them. `None` if
+    /// it accepts none of the host's CPUs.
+    fn parse_cpus(stat: &str, include: impl Fn(usize) -> bool) -> Option<Self> {
+        let cpus: Vec<Self> = stat
+            .lines()
+            .filter_map(|line| {
+                let (cpu, fields) = line.strip_prefix("cpu")?.split_once(' ')?;
+                include(cpu.parse().ok()?).then_some(fields)
+            })
+            .map(Self::parse_fields)
+            .collect::<Option<_>>()?;
+        cpus.into_iter().reduce(|a, b| Self {
+            busy: a.busy + b.busy,
+            idle: a.idle + b.idle,
+            steal: a.steal + b.steal,
+        })
+    }
+
+    /// Parses the fields of a "cpu" line, which are user, nice, system, idle, iowait, irq, softirq
+    /// and steal time.
+    fn parse_fields(fields: &str) -> Option<Self> {
+        let fields: Vec<u64> = fields
+            .split_whitespace()
+            .map(|f| f.parse().ok())
+            .collect::<Option<_>>()?;
+        let field = |i: usize| fields.get(i).copied().unwrap_or(0);
+        Some(Self {
+            busy: field(0) + field(1) + field(2) + field(5) + field(6),
+            idle: field(3) + field(4),
+            steal: field(7),
+        })
+    }
+
+    /// Gets how the CPU time since `earlier` was spent.
+    fn usage_since(&self, earlier: &CpuTimes) -> CpuUsage {
+        let busy = self.busy.saturating_sub(earlier.busy) as f64;
+        let idle = self.idle.saturating_sub(earlier.idle) as f64;
+        let steal = self.steal.saturating_sub(earlier.steal) as f64;
+        let total = busy + idle + steal;
+        if total == 0.0 {
+            return CpuUsage::default();
+        }
+        CpuUsage {
+            busy_percent: busy / total * 100.0,
+            steal_percent: steal / total * 100.0,
+        }
+    }
+}
+
+/// How the host's CPU time was spent over some period, as percentages of the total.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CpuUsage {
+    pub busy_percent: f64,
+    /// Time the hypervisor gave to other virtual machines.
+    pub steal_percent: f64,
+}
+
+/// The state of the host at one point during a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostSample {
+    /// When the sample was taken, relative to the start of the run.
+    pub elapsed: Duration,
+    /// CPU usage since the previous sample.
+    pub cpu: CpuUsage,
+    /// CPU usage since the previous sample on the CPUs outside the server and client cpusets.
+    /// `None` unless both cpusets are set and leave other CPUs free.
+    pub background_cpu: Option<CpuUsage>,
+    /// The 1 minute load average.
+    pub load_avg: Option<f64>,
+    /// The mean frequency of the host's CPUs.
+    pub cpu_mhz: Option<f64>,
+}
+
+fn read_load_avg() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// Reads the mean current frequency of the host's CPUs, from cpufreq if available, or else
+/// /proc/cpuinfo.
+fn read_cpu_mhz() -> Option<f64> {
+    let cpufreq_khz: Vec<f64> = fs::read_dir("/sys/devices/system/cpu")
+        .ok()?
+        .flatten()
+        .filter_map(|cpu| fs::read_to_string(cpu.path().join("cpufreq/scaling_cur_freq")).ok())
+        .filter_map(|khz| khz.trim().parse().ok())
+        .collect();
+    if !cpufreq_khz.is_empty() {
+        return Some(mean(&cpufreq_khz) / 1000.0);
+    }
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    let mhz: Vec<f64> = cpuinfo
+        .lines()
+        .filter(|line| line.starts_with("cpu MHz"))
+        .filter_map(|line| line.split_once(':')?.1.trim().parse().ok())
+        .collect();
+    (!mhz.is_empty()).then(|| mean(&mhz))
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn max_of(samples: &[HostSample], value: impl Fn(&HostSample) -> Option<f64>) -> Option<f64> {
+    samples.iter().filter_map(value).reduce(f64::max)
+}
+
+/// How far the CPU frequency fell below its highest value across the samples, as a percentage.
+fn cpu_mhz_drop_percent(samples: &[HostSample]) -> Option<f64> {
+    let max = max_of(samples, |s| s.cpu_mhz)?;
+    let min = samples.iter().filter_map(|s| s.cpu_mhz).reduce(f64::min)?;
+    (max > 0.0).then(|| (max - min) / max * 100.0)
+}
+
+/// How busy the host was around one benchmark run, and whether that makes the run's results
+/// suspect.
+#[derive(Debug)]
+pub struct NoiseReport<'a> {
+    pub target: TestTarget<'a>,
+    /// Identifies the run within the benchmark mode, e.g. the path being benchmarked.
+    pub run: String,
+    /// CPU usage while the server was idle, just before the run. `None` if it couldn't be read.
+    pub baseline: Option<CpuUsage>,
+    pub samples: Vec<HostSample>,
+    /// Why the run was too noisy to trust, if it was.
+    pub reasons: Vec<String>,
+}
+
+impl<'a> NoiseReport<'a> {
+    pub fn is_noisy(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+
+    pub fn max_steal_percent(&self) -> Option<f64> {
+        max_of(&self.samples, |s| Some(s.cpu.steal_percent))
+    }
+
+    pub fn max_background_cpu_percent(&self) -> Option<f64> {
+        max_of(&self.samples, |s| {
+            s.background_cpu.map(|cpu| cpu.busy_percent)
+        })
+    }
+
+    pub fn max_load_avg(&self) -> Option<f64> {
+        max_of(&self.samples, |s| s.load_avg)
+    }
+
+    pub fn min_cpu_mhz(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .filter_map(|s| s.cpu_mhz)
+            .reduce(f64::min)
+    }
+
+    pub fn max_cpu_mhz(&self) -> Option<f64> {
+        max_of(&self.samples, |s| s.cpu_mhz)
+    }
+
+    pub fn cpu_mhz_drop_percent(&self) -> Option<f64> {
+        cpu_mhz_drop_percent(&self.samples)
+    }
+}
+
+/// Finds why the host was too busy to trust a run, given its CPU usage before the run and the
+/// samples taken during it.
+fn find_reasons(
+    baseline: Option<CpuUsage>,
+    samples: &[HostSample],
+    num_cpus: usize,
+    config: &NoiseConfig,
+) -> Vec<String> {
+    let mut reasons = vec![];
+    if let Some(baseline) = baseline {
+        if baseline.busy_percent > config.max_background_cpu_percent {
+            reasons.push(format!(
+                "background CPU usage was {:.1}%",
+                baseline.busy_percent
+            ));
+        }
+        if baseline.steal_percent > config.max_steal_percent {
+            reasons.push(format!(
+                "steal time was {:.1}% before the run",
+                baseline.steal_percent
+            ));
+        }
+    }
+    if let Some(steal) = max_of(samples, |s| Some(s.cpu.steal_percent))
+        .filter(|steal| *steal > config.max_steal_percent)
+    {
+        reasons.push(format!("steal time reached {:.1}% during the run", steal));
+    }
+    if let Some(busy) = max_of(samples, |s| s.background_cpu.map(|cpu| cpu.busy_percent))
+        .filter(|busy| *busy > config.max_background_cpu_percent)
+    {
+        reasons.push(format!(
+            "background CPU usage reached {:.1}% during the run",
+            busy
+        ));
+    }
+    if let Some(load) = max_of(samples, |s| s.load_avg)
+        .map(|load| load / num_cpus as f64)
+        .filter(|load| *load > config.max_load_avg_per_cpu)
+    {
+        reasons.push(format!(
+            "load average reached {:.2} per CPU during the run",
+            load
+        ));
+    }
+    if let Some(drop) =
+        cpu_mhz_drop_percent(samples).filter(|drop| *drop > config.max_cpu_mhz_drop_percent)
+    {
+        reasons.push(format!("CPU frequency dropped {:.1}% during the run", drop));
+    }
+    reasons
+}
+
+fn too_noisy(target: &TestTarget, reasons: &[String]) -> Box<dyn Error> {
+    format!(
+        "Host is too busy to benchmark {}: {}",
+        target.name(),
+        reasons.join(", ")
+    )
+    .into()
+}
+
+/// Watches the host for other load while a benchmark runs, so that runs it may have disturbed can
+/// be flagged.
+pub struct NoiseMonitor {
+    config: NoiseConfig,
+    num_cpus: usize,
+    baseline: Option<CpuUsage>,
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<Vec<HostSample>>,
+}
+
+impl NoiseMonitor {
+    /// Measures the host's CPU usage while the server is idle, then samples the host in the
+    /// background until stopped.
+    ///
+    /// In strict mode, fails if the host is already too busy.
+    pub async fn start(
+        target: &TestTarget<'_>,
+        config: &NoiseConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let baseline = match CpuTimes::read() {
+            Ok(start) => {
+                tokio::time::sleep(BASELINE_DURATION).await;
+                CpuTimes::read().ok().map(|end| end.usage_since(&start))
+            }
+            Err(_) => None,
+        };
+        let num_cpus = cpuset::online_cpus();
+        let reasons = find_reasons(baseline, &[], num_cpus, config);
+        if config.strict && !reasons.is_empty() {
+            return Err(too_noisy(target, &reasons));
+        }
+
+        // Only other processes can be busy on the CPUs that neither the server nor the client is
+        // pinned to. Without both cpusets, their usage can't be told apart from the benchmark's.
+        let benchmark_cpus: Option<Vec<usize>> = match (target.server_cpuset, target.client_cpuset)
+        {
+            (Some(server), Some(client)) => Some([server.cpus(), client.cpus()].concat()),
+            _ => None,
+        };
+        let read_times = move || {
+            let stat = fs::read_to_string("/proc/stat").ok()?;
+            let background = benchmark_cpus.as_ref().and_then(|benchmark_cpus| {
+                CpuTimes::parse_cpus(&stat, |cpu| !benchmark_cpus.contains(&cpu))
+            });
+            Some((CpuTimes::parse(&stat)?, background))
+        };
+
+        let (stop, mut stopped) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let start = Instant::now();
+            let mut samples = vec![];
+            let mut last_times = read_times();
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The first tick completes immediately, before there's any usage to measure.
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = interval.tick() => {
+                        let times = read_times();
+                        if let (Some(last), Some(times)) = (last_times, times) {
+                            samples.push(HostSample {
+                                elapsed: start.elapsed(),
+                                cpu: times.0.usage_since(&last.0),
+                                background_cpu: times.1.zip(last.1).map(|(times, last)| {
+                                    times.usage_since(&last)
+                                }),
+                                load_avg: read_load_avg(),
+                                cpu_mhz: read_cpu_mhz(),
+                            });
+                        }
+                        last_times = times;
+                    }
+                }
+            }
+            samples
+        });
+        Ok(Self {
+            config: *config,
+            num_cpus,
+            baseline,
+            stop,
+            handle,
+        })
+    }
+
+    /// Stops sampling, and reports whether the host was too busy for the run to be trusted.
+    ///
+    /// In strict mode, fails if it was.
+    pub async fn stop<'a>(
+        self,
+        target: TestTarget<'a>,
+        run: String,
+    ) -> Result<NoiseReport<'a>, Box<dyn Error>> {
+        let _ = self.stop.send(());
+        let samples = self.handle.await.unwrap_or_default();
+        let mut report = NoiseReport {
+            target,
+            run,
+            baseline: self.baseline,
+            samples,
+            reasons: vec![],
+        };
+        report.reasons = find_reasons(self.baseline, &report.samples, self.num_cpus, &self.config);
+        if report.is_noisy() {
+            if self.config.strict {
+                return Err(too_noisy(&report.target, &report.reasons));
+            }
+            eprintln!(
+                "Flagging {} run {} as noisy: {}",
+                report.target.name(),
+                report.run,
+                report.reasons.join(", ")
+            );
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cpu_usage_from_stat() {
+        let start = CpuTimes::parse("cpu  100 0 50 800 50 0 0 0 0 0\ncpu0 1 2 3\n").unwrap();
+        let end = CpuTimes::parse("cpu  150 10 80 900 60 0 0 20 0 0\n").unwrap();
+        let usage = end.usage_since(&start);
+        assert_relative_eq!(usage.busy_percent, 90.0 / 220.0 * 100.0);
+        assert_relative_eq!(usage.steal_percent, 20.0 / 220.0 * 100.0);
+
+        assert_eq!(CpuTimes::parse("intr 1 2 3\n"), None);
+        assert_eq!(start.usage_since(&start), CpuUsage::default());
+    }
+
+    #[test]
+    fn test_background_cpu_times_from_stat() {
+        let stat = "cpu  30 0 0 30 0 0 0 0\ncpu0 10 0 0 10 0 0 0 0\ncpu1 20 0 0 20 0 0 0 0\n";
+        let times = CpuTimes::parse_cpus(stat, |cpu| cpu != 0).unwrap();
+        assert_eq!(times.busy, 20);
+        assert_eq!(CpuTimes::parse_cpus(stat, |_| true), CpuTimes::parse(stat));
+        assert_eq!(CpuTimes::parse_cpus(stat, |_| false), None);
+    }
+
+    fn sample(steal_percent: f64, background_percent: f64, load_avg: f64, mhz: f64) -> HostSample {
+        HostSample {
+            elapsed: Duration::ZERO,
+            cpu: CpuUsage {
+                busy_percent: 100.0,
+                steal_percent,
+            },
+            background_cpu: Some(CpuUsage {
+                busy_percent: background_percent,
+                steal_percent: 0.0,
+            }),
+            load_avg: Some(load_avg),
+            cpu_mhz: Some(mhz),
+        }
+    }
+
+    #[test]
+    fn test_find_reasons() {
+        let config = NoiseConfig::default();
+        let quiet = CpuUsage {
+            busy_percent: 2.0,
+            steal_percent: 0.0,
+        };
+        let calm = [sample(1.0, 2.0, 3.0, 3000.0), sample(0.0, 1.0, 4.0, 2900.0)];
+        assert!(find_reasons(Some(quiet), &calm, 4, &config).is_empty());
+        assert!(find_reasons(None, &[], 4, &config).is_empty());
+
+        let busy = CpuUsage {
+            busy_percent: 40.0,
+            steal_percent: 6.0,
+        };
+        assert_eq!(find_reasons(Some(busy), &[], 4, &config).len(), 2);
+
+        let stolen = [sample(12.0, 2.0, 3.0, 3000.0)];
+        assert_eq!(find_reasons(Some(quiet), &stolen, 4, &config).len(), 1);
+        let background = [sample(0.0, 30.0, 3.0, 3000.0)];
+        assert_eq!(find_reasons(Some(quiet), &background, 4, &config).len(), 1);
+        let loaded = [sample(0.0, 2.0, 12.0, 3000.0)];
+        assert_eq!(find_reasons(Some(quiet), &loaded, 4, &config).len(), 1);
+        assert!(find_reasons(Some(quiet), &loaded, 8, &config).is_empty());
+        let throttled = [sample(0.0, 2.0, 3.0, 3000.0), sample(0.0, 2.0, 3.0, 2000.0)];
+        assert_eq!(find_reasons(Some(quiet), &throttled, 4, &config).len(), 1);
+    }
+}
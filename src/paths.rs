@@ -1,5 +1,5 @@
 /// Def ines a test path.
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
 pub struct TestPath {
     /// The path to test, not including the domain.
     pub path: String,
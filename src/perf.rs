@@ -1,96 +1,411 @@
-use std::{error::Error, path::PathBuf, process::Command, io};
+use std::{error::Error, io, path::PathBuf, process::Command, time::Duration};
 
-use crate::{docker, metrics::Metric, paths::TestPath, targets::TestTarget, writes};
+use crate::{
+    config::{HealthConfig, NoiseConfig, PerfConfig},
+    health,
+    metrics::{LatencyDistribution, Metric, MetricData, ParseError, SocketErrors, Totals},
+    native::{self, RequestTiming},
+    noise::NoiseMonitor,
+    paths::TestPath,
+    runtime::{self, Runtimes},
+    stats::ResourceSampler,
+    targets::TestTarget,
+    writes,
+};
+
+/// The engine used to send requests during performance benchmarks.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// Sends requests in-process, recording the timing of every request.
+    Native,
+    /// Runs the external `wrk` binary and parses its summary output.
+    Wrk,
+}
 
 #[derive(Debug)]
 pub struct PerfResult<'a, 'b> {
     pub target: TestTarget<'b>,
     pub path: &'a TestPath,
     pub metrics: Vec<Metric>,
+    /// The timing of each individual request. Only recorded by the native engine.
+    pub timings: Vec<RequestTiming>,
+    /// The constant request rate this result was measured at, for open-loop benchmarks.
+    pub target_rate: Option<f64>,
+    /// The number of threads wrk sent requests from, if it was used.
+    pub threads: Option<usize>,
+    /// The number of concurrent connections requests were sent over, for closed-loop benchmarks.
+    /// Open-loop benchmarks send each request when it's due, however many are already in flight.
+    pub connections: Option<usize>,
+    /// Whether the host was too busy around the benchmark for its results to be trusted.
+    pub is_noisy: bool,
+    /// Why the path couldn't be benchmarked, if it couldn't.
+    pub error: Option<String>,
 }
 
 impl<'a, 'b> PerfResult<'a, 'b> {
-    fn new<'c: 'a>(target: TestTarget<'b>, path: &'c TestPath) -> Self {
+    fn new<'c: 'a>(target: TestTarget<'b>, path: &'c TestPath, connections: Option<usize>) -> Self {
         PerfResult {
-            target: target,
-            path: path,
+            target,
+            path,
             metrics: vec![],
+            timings: vec![],
+            target_rate: None,
+            threads: None,
+            connections,
+            is_noisy: false,
+            error: None,
+        }
+    }
+
+    fn push_native_results(&mut self, timings: Vec<RequestTiming>) {
+        self.metrics.extend(native::metrics_from_timings(&timings));
+        self.timings = timings;
+    }
+
+    fn push_wrk_results(&mut self, out: &[u8]) -> Result<(), ParseError> {
+        let out = String::from_utf8_lossy(out);
+        self.metrics.extend(Metric::try_all_from_wrk_output(&out)?);
+        self.metrics.extend(
+            LatencyDistribution::try_from_wrk_output(&out)?.map(Metric::LatencyDistribution),
+        );
+        // wrk only reports errors when there were some.
+        if self.totals().is_some() {
+            if self.socket_errors().is_none() {
+                self.metrics
+                    .push(Metric::SocketErrors(SocketErrors::default()));
+            }
+            if self.non_2xx_3xx_responses().is_none() {
+                self.metrics.push(Metric::Non2xx3xxResponses(0));
+            }
+        }
+        // wrk's throughput counts every response, whatever its status, so the failed ones are
+        // left out to match the native engine, which only counts successful requests.
+        let success_ratio = match (self.totals(), self.non_2xx_3xx_responses()) {
+            (Some(totals), Some(failed)) if failed > 0 && totals.requests > 0 => {
+                Some(totals.requests.saturating_sub(failed) as f64 / totals.requests as f64)
+            }
+            _ => None,
+        };
+        if let Some(ratio) = success_ratio {
+            for metric in self.metrics.iter_mut() {
+                if let Metric::RequestsPerSec(rate) = metric {
+                    *rate *= ratio;
+                }
+            }
         }
+        Ok(())
+    }
+
+    /// Records an error if the benchmark otherwise succeeded without recording latency and
+    /// throughput, e.g. because no requests completed.
+    fn check_metrics(&mut self) {
+        if self.error.is_none() && (self.latency().is_none() || self.requests_per_sec().is_none()) {
+            self.error = Some(String::from("No latency or throughput was recorded"));
+        }
+    }
+
+    /// Gets the latency metric, if one was recorded.
+    pub fn latency(&self) -> Option<&MetricData<Duration>> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::Latency(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    /// Gets the throughput of each of wrk's threads, if it was recorded.
+    pub fn thread_qps(&self) -> Option<&MetricData<f64>> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::ThreadQps(data) => Some(data),
+            _ => None,
+        })
     }
 
-    fn push_wrk_results(&mut self, out: Vec<u8>) {
-        let out_str = std::str::from_utf8(&out).unwrap();
-        let metrics = out_str
-            .lines()
-            .map(|line| Metric::try_from_wrk_output(line));
-        self.metrics.extend(metrics.filter_map(|m| m));
+    /// Gets the full latency distribution, if one was recorded.
+    pub fn latency_distribution(&self) -> Option<&LatencyDistribution> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::LatencyDistribution(distribution) => Some(distribution),
+            _ => None,
+        })
+    }
+
+    /// Gets the totals for the whole run, if they were recorded.
+    pub fn totals(&self) -> Option<&Totals> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::Totals(totals) => Some(totals),
+            _ => None,
+        })
+    }
+
+    /// Gets the total throughput, in requests per second, if it was recorded.
+    pub fn requests_per_sec(&self) -> Option<f64> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::RequestsPerSec(rate) => Some(*rate),
+            _ => None,
+        })
+    }
+
+    /// Gets the mean bytes read per second across all threads, if it was recorded.
+    pub fn transfer_per_sec(&self) -> Option<f64> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::TransferPerSec(rate) => Some(*rate),
+            _ => None,
+        })
+    }
+
+    /// Gets the socket errors, if they were recorded.
+    pub fn socket_errors(&self) -> Option<&SocketErrors> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::SocketErrors(errors) => Some(errors),
+            _ => None,
+        })
+    }
+
+    /// Gets the number of responses that weren't 2xx or 3xx, if it was recorded.
+    pub fn non_2xx_3xx_responses(&self) -> Option<u64> {
+        self.metrics.iter().find_map(|m| match m {
+            Metric::Non2xx3xxResponses(count) => Some(*count),
+            _ => None,
+        })
     }
 }
 
-fn warm_up(path: &str) -> io::Result<()>{
-    Command::new("wrk")
-        .args(["-t", "1", "-c", "1", "-d", "1s", &path])
+/// Builds a wrk command that runs for `duration_secs` and accepts the target's encoding, if any.
+///
+/// With a rate, `program` must be wrk2, which sends requests at that rate.
+fn wrk_command(
+    program: &str,
+    target: &TestTarget,
+    config: &PerfConfig,
+    duration_secs: u64,
+    rate: Option<f64>,
+) -> Command {
+    let mut command = Command::new(program);
+    if let Some(accept_encoding) = target.encoding.accept_encoding() {
+        command.args(["-H", &format!("Accept-Encoding: {}", accept_encoding)]);
+    }
+    command
+        .args(["-t", &config.threads.to_string()])
+        .args(["-c", &config.connections.to_string()])
+        .args(["-d", &format!("{}s", duration_secs)]);
+    if let Some(rate) = rate {
+        // wrk2 only takes whole rates.
+        let rate = (rate.round() as u64).max(1);
+        command.args(["-R", &rate.to_string(), "-U"]);
+    }
+    command
+}
+
+fn warm_up(
+    program: &str,
+    target: &TestTarget,
+    path: &str,
+    config: &PerfConfig,
+    rate: Option<f64>,
+) -> io::Result<()> {
+    wrk_command(program, target, config, config.warm_up_secs, rate)
+        .arg(path)
         .output()?;
     Ok(())
 }
 
-fn bench_path<'a: 'c, 'b, 'c>(
+/// Benchmarks a path with wrk, or with wrk2 at a constant rate if one is given.
+fn bench_path_with_wrk<'a: 'c, 'b, 'c>(
     target: TestTarget<'b>,
     path: &'a TestPath,
+    full_path: &str,
+    program: &str,
+    rate: Option<f64>,
+    config: &PerfConfig,
 ) -> Result<PerfResult<'a, 'b>, Box<dyn Error>> {
-    let full_path = format!("http://localhost:8080{}", &path.path);
-
-    warm_up(&full_path)?;
+    warm_up(program, &target, full_path, config, rate)?;
 
-    let out = Command::new("wrk")
-        .args(["-t", "1", "-c", "1", "-d", "10s", &full_path])
+    let out = wrk_command(program, &target, config, config.duration_secs, rate)
+        .args(["--latency", full_path])
         .output()?;
+    let mut result = PerfResult::new(target, path, Some(config.connections));
+    result.threads = Some(config.threads);
     if !out.status.success() {
-        return Err(format!("Failed to run wrk; code: {:?}", out.status.code()).into());
+        result.error = Some(format!(
+            "{} failed with code {:?}: {}",
+            program,
+            out.status.code(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    } else if let Err(e) = result.push_wrk_results(&out.stdout) {
+        result.error = Some(e.to_string());
+    }
+    Ok(result)
+}
+
+async fn bench_path_natively<'a: 'c, 'b, 'c>(
+    target: TestTarget<'b>,
+    path: &'a TestPath,
+    full_path: &str,
+    config: &PerfConfig,
+) -> Result<PerfResult<'a, 'b>, Box<dyn Error>> {
+    let client = native::client_for(&target, config.connections)?;
+    let warm_up_duration = Duration::from_secs(config.warm_up_secs);
+    native::run_closed_loop(&client, full_path, config.connections, warm_up_duration).await;
+
+    let duration = Duration::from_secs(config.duration_secs);
+    let timings = native::run_closed_loop(&client, full_path, config.connections, duration).await;
+    let mut result = PerfResult::new(target, path, Some(config.connections));
+    result.push_native_results(timings);
+    Ok(result)
+}
+
+async fn bench_path_open_loop<'a: 'c, 'b, 'c>(
+    target: TestTarget<'b>,
+    path: &'a TestPath,
+    rate: f64,
+    config: &PerfConfig,
+) -> Result<PerfResult<'a, 'b>, Box<dyn Error>> {
+    let full_path = target.url(&path.path);
+    let mut result = match &config.wrk2 {
+        Some(wrk2) => bench_path_with_wrk(target, path, &full_path, wrk2, Some(rate), config)?,
+        None => {
+            let client = native::client_for(&target, config.connections)?;
+            let warm_up_duration = Duration::from_secs(config.warm_up_secs);
+            native::run_closed_loop(&client, &full_path, config.connections, warm_up_duration)
+                .await;
+
+            let duration = Duration::from_secs(config.duration_secs);
+            let timings = native::run_open_loop(&client, &full_path, rate, duration).await;
+            let mut result = PerfResult::new(target, path, None);
+            result.push_native_results(timings);
+            result
+        }
+    };
+    result.target_rate = Some(rate);
+    result.check_metrics();
+    match &result.error {
+        Some(e) => eprintln!("\tFailed to benchmark path at {} req/s: {}", rate, e),
+        None => println!(
+            "\tOpen-loop latency at {} req/s: {:?}",
+            rate,
+            result.latency()
+        ),
+    }
+
+    Ok(result)
+}
+
+async fn bench_path<'a: 'c, 'b, 'c>(
+    target: TestTarget<'b>,
+    path: &'a TestPath,
+    config: &PerfConfig,
+) -> Result<PerfResult<'a, 'b>, Box<dyn Error>> {
+    let full_path = target.url(&path.path);
+
+    let mut result = match config.engine {
+        Engine::Native => bench_path_natively(target, path, &full_path, config).await?,
+        Engine::Wrk => bench_path_with_wrk(target, path, &full_path, "wrk", None, config)?,
+    };
+    result.check_metrics();
+    match (&result.error, result.latency()) {
+        (Some(e), _) => eprintln!("\tFailed to benchmark path: {}", e),
+        (None, latency) => println!("\tLatency: {:?}", latency),
+    }
+    if let Some(count) = result.non_2xx_3xx_responses().filter(|count| *count > 0) {
+        eprintln!("\t{} responses were not 2xx or 3xx", count);
     }
-    let mut result = PerfResult::new(target, path);
-    result.push_wrk_results(out.stdout);
-    println!("\tLatency: {:?}", result.metrics.iter().find(|m| matches!(m, Metric::Latency(_))).unwrap());
 
     Ok(result)
 }
 
 /// Benchmarks each target, writing results to a CSV in out_dir.
+///
+/// Each path's latency distribution is written to latency-distribution.csv. When using the native
+/// engine, the timing of every request is also written to requests.csv. If a rate is configured,
+/// each path is also benchmarked at that constant rate, natively or with wrk2, with results written
+/// to open-loop-benchmarks.csv. How busy the host was around each run is written to noise.csv.
 pub async fn benchmark_all<'a>(
     targets: &Vec<TestTarget<'a>>,
     out_dir: PathBuf,
+    config: &PerfConfig,
+    health_config: &HealthConfig,
+    noise_config: &NoiseConfig,
+    runtimes: &Runtimes,
 ) -> Result<(), Box<dyn Error>> {
-    let mut perf_benchmark_path = out_dir;
+    let mut perf_benchmark_path = out_dir.clone();
     perf_benchmark_path.push("benchmarks.csv");
     let mut benchmark_csv = csv::Writer::from_path(&perf_benchmark_path)?;
-
-    lazy_static! {
-        static ref TEST_PATHS: [TestPath; 9] = [
-            TestPath::new("/strings/hello", "hello"),
-            TestPath::new("/strings/hello?name=fluffy%20dog", "hello-param"),
-            TestPath::new("/strings/hello?name=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "hello-long"),
-            TestPath::new("/strings/async-hello", "async-hello"),
-            TestPath::new("/strings/lines?n=50000", "lines"),
-            TestPath::new("/static/scout.webp", "static-img"),
-            TestPath::new("/static/basic.html", "static-text"),
-            TestPath::new("/math/power-reciprocals-alt?n=10000", "math-powers-light"),
-            TestPath::new("/math/power-reciprocals-alt?n=10000000", "math-powers-heavy")
-        ];
-    }
+    let mut requests_csv_path = perf_benchmark_path.clone();
+    requests_csv_path.set_file_name("requests.csv");
+    // Only the native engine records the timing of each request.
+    let is_native_open_loop = config.rate.is_some() && config.wrk2.is_none();
+    let mut requests_csv = match config.engine == Engine::Native || is_native_open_loop {
+        true => Some(csv::Writer::from_path(&requests_csv_path)?),
+        false => None,
+    };
+    let mut samples_csv_path = perf_benchmark_path.clone();
+    samples_csv_path.set_file_name("resource-samples.csv");
+    let mut samples_csv = csv::Writer::from_path(&samples_csv_path)?;
+    let mut summary_csv_path = perf_benchmark_path.clone();
+    summary_csv_path.set_file_name("resource-summary.csv");
+    let mut summary_csv = csv::Writer::from_path(&summary_csv_path)?;
+    let mut noise_csv_path = perf_benchmark_path.clone();
+    noise_csv_path.set_file_name("noise.csv");
+    let mut noise_csv = csv::Writer::from_path(&noise_csv_path)?;
+    let mut distribution_csv_path = perf_benchmark_path.clone();
+    distribution_csv_path.set_file_name("latency-distribution.csv");
+    let mut distribution_csv = csv::Writer::from_path(&distribution_csv_path)?;
+    let mut open_loop_csv_path = perf_benchmark_path.clone();
+    open_loop_csv_path.set_file_name("open-loop-benchmarks.csv");
+    let mut open_loop_csv = match config.rate {
+        Some(_) => Some(csv::Writer::from_path(&open_loop_csv_path)?),
+        None => None,
+    };
 
     for target in targets {
-        let name = docker::start_container(&target)?;
-        docker::await_healthy().await;
+        let target = &target.with_host_port()?;
+        let log_path = runtime::prep_log_path(&out_dir, target)?;
+        let Some(container) =
+            health::start_healthy(runtimes, target, &log_path, health_config).await?
+        else {
+            continue;
+        };
 
         println!("Starting performance benchmark on target {}", target.name());
-        for path in TEST_PATHS.iter() {
+        for path in config.paths.iter() {
             println!("Benchmarking path {:?}", path);
-            let result = bench_path(target.clone(), path)?;
+            let monitor = NoiseMonitor::start(target, noise_config).await?;
+            let sampler = ResourceSampler::start(container.runtime().clone(), &container.id);
+            let mut result = bench_path(target.clone(), path, config).await?;
+            let usage = sampler.stop(target.clone(), path.name.clone()).await;
+            let noise = monitor.stop(target.clone(), path.name.clone()).await?;
+            result.is_noisy = noise.is_noisy();
+            writes::write_noise(&mut noise_csv, &noise)?;
+            writes::write_resource_usage(&mut samples_csv, &mut summary_csv, &usage)?;
+            if let Some(requests_csv) = requests_csv.as_mut() {
+                writes::write_perf_request_timings(requests_csv, &result)?;
+            }
+            writes::write_latency_distribution(&mut distribution_csv, &result)?;
             writes::write_perf_result(&mut benchmark_csv, result)?;
+
+            if let (Some(rate), Some(open_loop_csv)) = (config.rate, open_loop_csv.as_mut()) {
+                let run = format!("{}-open-loop", path.name);
+                let monitor = NoiseMonitor::start(target, noise_config).await?;
+                let sampler = ResourceSampler::start(container.runtime().clone(), &container.id);
+                let mut result = bench_path_open_loop(target.clone(), path, rate, config).await?;
+                let usage = sampler.stop(target.clone(), run.clone()).await;
+                let noise = monitor.stop(target.clone(), run).await?;
+                result.is_noisy = noise.is_noisy();
+                writes::write_noise(&mut noise_csv, &noise)?;
+                writes::write_resource_usage(&mut samples_csv, &mut summary_csv, &usage)?;
+                if let Some(requests_csv) = requests_csv.as_mut() {
+                    writes::write_perf_request_timings(requests_csv, &result)?;
+                }
+                writes::write_latency_distribution(&mut distribution_csv, &result)?;
+                writes::write_perf_result(open_loop_csv, result)?;
+            }
         }
         println!("Finished performance benchmark on target {}", target.name());
 
-        docker::kill_container(&name)?;
+        container.stop().await?;
     }
 
     Ok(())
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, fs, io, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{process::Child, sync::Mutex};
+
+use crate::{
+    cpuset,
+    runtime::{ContainerRuntime, ContainerState, ContainerStats, RuntimeError},
+    targets::TestTarget,
+};
+
+/// How long to measure CPU usage over when taking stats.
+const CPU_SAMPLE_DURATION: Duration = Duration::from_millis(500);
+/// How long to wait for a process to exit after SIGTERM before killing it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs targets as local processes rather than containers.
+///
+/// Each process is pinned to the first `num_cpus` CPUs of the target's server cpuset, or if it has
+/// none, of the host's CPUs outside the client cpuset, and its data segment is limited to
+/// `ram_mb`. It is given the port to listen on in the `PORT` environment variable, and its stdout
+/// and stderr are written to a log file in the temp directory until it is removed.
+///
+/// The limit is `RLIMIT_DATA` rather than `RLIMIT_AS`, since Go, the JVM and Node reserve far more
+/// address space than they use, and fail to start under an address space limit. It only counts
+/// the heap and private writable mappings, so memory such as thread stacks and file mappings isn't
+/// limited, and it isn't enforced on RSS the way a container's memory limit is.
+#[derive(Debug, Default)]
+pub struct ProcessRuntime {
+    /// Running processes, keyed by PID.
+    children: Mutex<HashMap<String, Process>>,
+}
+
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    log_path: PathBuf,
+}
+
+impl ProcessRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Limits the calling process to the given CPUs, and `memory_bytes` of data segment.
+///
+/// Only calls async-signal-safe functions, so that it can run between fork and exec.
+fn restrict_self(cpus: &[usize], memory_bytes: u64) -> io::Result<()> {
+    cpuset::set_affinity(0, cpus)?;
+    let limit = libc::rlimit {
+        rlim_cur: memory_bytes,
+        rlim_max: memory_bytes,
+    };
+    // SAFETY: setrlimit only reads from the value passed to it.
+    if unsafe { libc::setrlimit(libc::RLIMIT_DATA, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads the total user and system CPU time used by a process, in clock ticks.
+fn read_cpu_ticks(pid: &str) -> io::Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name may contain spaces, so skip past its closing parenthesis. utime and stime
+    // are then the 12th and 13th fields.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().collect())
+        .unwrap_or_default();
+    let ticks = |i: usize| -> io::Result<u64> {
+        fields
+            .get(i)
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("Unexpected /proc/{}/stat format", pid)))
+    };
+    Ok(ticks(11)? + ticks(12)?)
+}
+
+/// Reads the resident set size of a process, in bytes.
+fn read_rss_bytes(pid: &str) -> io::Result<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| io::Error::other(format!("No VmRSS in /proc/{}/status", pid)))
+}
+
+#[async_trait]
+impl ContainerRuntime for ProcessRuntime {
+    async fn start_container(&self, target: &TestTarget<'_>) -> Result<String, RuntimeError> {
+        let (program, args) =
+            target
+                .command
+                .split_first()
+                .ok_or_else(|| RuntimeError::CommandNotFound {
+                    server_name: target.server_name.to_string(),
+                    program: None,
+                })?;
+
+        println!(
+            "Starting process {} with command {:?}",
+            target.name(),
+            target.command
+        );
+        let cpus = target.server_cpus().cpus().to_vec();
+        let memory_bytes = target.ram_mb as u64 * 1024 * 1024;
+        let log_path = std::env::temp_dir().join(format!("{}.log", target.name()));
+        let log = fs::File::create(&log_path)?;
+        let mut command = tokio::process::Command::new(program);
+        command
+            .args(args)
+            .env("PORT", target.host_port.to_string())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true);
+        // SAFETY: restrict_self is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || restrict_self(&cpus, memory_bytes));
+        }
+        let child = command.spawn().map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => RuntimeError::CommandNotFound {
+                server_name: target.server_name.to_string(),
+                program: Some(program.clone()),
+            },
+            _ => e.into(),
+        })?;
+
+        let pid = child.id().map(|id| id.to_string()).unwrap_or_default();
+        self.children
+            .lock()
+            .await
+            .insert(pid.clone(), Process { child, log_path });
+        Ok(pid)
+    }
+
+    async fn stop_container(&self, id: &str) -> Result<(), RuntimeError> {
+        println!("Killing process {}", id);
+        let mut children = self.children.lock().await;
+        let child = &mut children
+            .get_mut(id)
+            .ok_or_else(|| RuntimeError::ContainerNotFound(id.to_string()))?
+            .child;
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        if let Some(pid) = child.id() {
+            // SAFETY: The child has not been reaped, so the PID still refers to it.
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+            }
+        }
+        if tokio::time::timeout(STOP_TIMEOUT, child.wait())
+            .await
+            .is_err()
+        {
+            child.kill().await?;
+        }
+        Ok(())
+    }
+
+    async fn container_state(&self, id: &str) -> Result<ContainerState, RuntimeError> {
+        let mut children = self.children.lock().await;
+        let process = children
+            .get_mut(id)
+            .ok_or_else(|| RuntimeError::ContainerNotFound(id.to_string()))?;
+        let status = process.child.try_wait()?;
+        Ok(ContainerState {
+            is_running: status.is_none(),
+            exit_code: status.and_then(|s| s.code()).map(i64::from),
+            // Exceeding the data segment limit makes allocations fail, rather than getting the
+            // process killed, so this can't be detected.
+            is_oom_killed: false,
+        })
+    }
+
+    /// Gets the process's CPU and memory usage. Processes share the host's network, so network
+    /// usage can't be attributed to them and is always reported as zero.
+    async fn container_stats(&self, id: &str) -> Result<ContainerStats, RuntimeError> {
+        // SAFETY: sysconf has no preconditions.
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let start_ticks = read_cpu_ticks(id)?;
+        tokio::time::sleep(CPU_SAMPLE_DURATION).await;
+        let end_ticks = read_cpu_ticks(id)?;
+        let cpu_secs = end_ticks.saturating_sub(start_ticks) as f64 / ticks_per_sec;
+        Ok(ContainerStats {
+            cpu_percent: cpu_secs / CPU_SAMPLE_DURATION.as_secs_f64() * 100.0,
+            memory_bytes: read_rss_bytes(id)?,
+            net_rx_bytes: 0,
+            net_tx_bytes: 0,
+        })
+    }
+
+    /// Forgets the process, and deletes its log file.
+    async fn remove_container(&self, id: &str) -> Result<(), RuntimeError> {
+        let process = self
+            .children
+            .lock()
+            .await
+            .remove(id)
+            .ok_or_else(|| RuntimeError::ContainerNotFound(id.to_string()))?;
+        fs::remove_file(process.log_path)?;
+        Ok(())
+    }
+
+    async fn container_logs(&self, id: &str, tail: Option<usize>) -> Result<String, RuntimeError> {
+        let log_path = self
+            .children
+            .lock()
+            .await
+            .get(id)
+            .map(|process| process.log_path.clone())
+            .ok_or_else(|| RuntimeError::ContainerNotFound(id.to_string()))?;
+        let logs = fs::read_to_string(log_path)?;
+        let Some(tail) = tail else {
+            return Ok(logs);
+        };
+        let lines: Vec<&str> = logs.lines().collect();
+        let tail_lines = &lines[lines.len().saturating_sub(tail)..];
+        Ok(tail_lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect())
+    }
+}
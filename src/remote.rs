@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+
+use crate::{
+    runtime::{ContainerRuntime, ContainerState, ContainerStats, RuntimeError},
+    targets::TestTarget,
+};
+
+/// Benchmarks servers that are already running elsewhere, at their target's base URL.
+///
+/// Their lifecycle isn't managed, so starting and stopping them does nothing, and they are always
+/// assumed to be running. Their resource usage can't be measured.
+#[derive(Debug, Default)]
+pub struct RemoteRuntime;
+
+#[async_trait]
+impl ContainerRuntime for RemoteRuntime {
+    async fn start_container(&self, target: &TestTarget<'_>) -> Result<String, RuntimeError> {
+        println!(
+            "Using remote server {} at {}",
+            target.name(),
+            target.base_url()
+        );
+        Ok(target.base_url())
+    }
+
+    async fn stop_container(&self, _id: &str) -> Result<(), RuntimeError> {
+        Ok(())
+    }
+
+    async fn container_state(&self, _id: &str) -> Result<ContainerState, RuntimeError> {
+        Ok(ContainerState {
+            is_running: true,
+            exit_code: None,
+            is_oom_killed: false,
+        })
+    }
+
+    async fn container_stats(&self, _id: &str) -> Result<ContainerStats, RuntimeError> {
+        Err(RuntimeError::Unsupported(
+            "resource usage of remote servers",
+        ))
+    }
+
+    async fn container_logs(
+        &self,
+        _id: &str,
+        _tail: Option<usize>,
+    ) -> Result<String, RuntimeError> {
+        Err(RuntimeError::Unsupported("logs of remote servers"))
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt, fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::task::JoinHandle;
+
+use crate::{
+    docker::DockerRuntime, process::ProcessRuntime, remote::RemoteRuntime, targets::TestTarget,
+};
+
+/// Selects how a target is run.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeKind {
+    /// Runs the target's image with Docker.
+    #[default]
+    Docker,
+    /// Runs the target's image with Podman.
+    Podman,
+    /// Runs the target's command as a local process.
+    Process,
+    /// Sends requests to a server that is already running at the target's base URL, without
+    /// starting or stopping it.
+    Remote,
+}
+
+/// The runtimes available to run targets with.
+pub struct Runtimes {
+    docker: Arc<dyn ContainerRuntime>,
+    podman: Arc<dyn ContainerRuntime>,
+    process: Arc<dyn ContainerRuntime>,
+    remote: Arc<dyn ContainerRuntime>,
+    /// Stops containers whose guards were dropped without being stopped.
+    cleanups: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Runtimes {
+    pub fn from_env() -> Self {
+        Self {
+            docker: Arc::new(DockerRuntime::from_env()),
+            podman: Arc::new(DockerRuntime::podman_from_env()),
+            process: Arc::new(ProcessRuntime::new()),
+            remote: Arc::new(RemoteRuntime),
+            cleanups: Arc::default(),
+        }
+    }
+
+    /// Starts a container for the target, which is stopped when the returned guard is dropped.
+    ///
+    /// The container's logs are appended to `log_path` when it is stopped.
+    pub async fn start(
+        &self,
+        target: &TestTarget<'_>,
+        log_path: &Path,
+    ) -> Result<Container, RuntimeError> {
+        let runtime = self.for_target(target).clone();
+        let id = runtime.start_container(target).await?;
+        Ok(Container {
+            id,
+            runtime,
+            log_path: log_path.to_path_buf(),
+            cleanups: self.cleanups.clone(),
+            is_stopped: false,
+        })
+    }
+
+    /// Inspects the image of each target's server, keyed by server name. Servers that aren't run
+    /// from images are left out.
+    pub async fn inspect_images(
+        &self,
+        targets: &[TestTarget<'_>],
+    ) -> Result<HashMap<String, ImageInfo>, RuntimeError> {
+        let mut images = HashMap::new();
+        for target in targets {
+            if images.contains_key(target.server_name) {
+                continue;
+            }
+            if let Some(image) = self.for_target(target).inspect_image(target).await? {
+                images.insert(target.server_name.to_string(), image);
+            }
+        }
+        Ok(images)
+    }
+
+    /// Builds the image of each target's server from `<source_dir>/<server_name>`, which must
+    /// contain a Dockerfile. Returns how long each build took, keyed by server name.
+    ///
+    /// Stops at the first build that fails. Servers that aren't run from images are left out.
+    pub async fn build_images(
+        &self,
+        targets: &[TestTarget<'_>],
+        source_dir: &Path,
+    ) -> Result<HashMap<String, Duration>, RuntimeError> {
+        let mut build_times = HashMap::new();
+        for target in targets {
+            if build_times.contains_key(target.server_name) {
+                continue;
+            }
+            let context_dir = source_dir.join(target.server_name);
+            let start = Instant::now();
+            let built = self
+                .for_target(target)
+                .build_image(target, &context_dir)
+                .await
+                .inspect_err(|e| eprintln!("{}", e))?;
+            if built {
+                let build_time = start.elapsed();
+                println!("Built {} in {:?}", target.docker_target(), build_time);
+                build_times.insert(target.server_name.to_string(), build_time);
+            }
+        }
+        Ok(build_times)
+    }
+
+    /// Waits until every container whose guard was dropped has been stopped.
+    pub async fn finish_cleanup(&self) {
+        let cleanups: Vec<_> = self.cleanups.lock().unwrap().drain(..).collect();
+        futures::future::join_all(cleanups).await;
+    }
+
+    /// Removes containers left behind by earlier runs, returning their IDs.
+    pub async fn remove_stale_containers(&self) -> Vec<String> {
+        let mut removed = vec![];
+        for (name, runtime) in [("docker", &self.docker), ("podman", &self.podman)] {
+            match runtime.remove_stale_containers().await {
+                Ok(ids) => removed.extend(ids),
+                Err(e) => eprintln!("Could not clean up {} containers: {}", name, e),
+            }
+        }
+        removed
+    }
+
+    /// Gets the version of each container runtime that can be reached, keyed by name.
+    pub async fn versions(&self) -> BTreeMap<&'static str, String> {
+        let mut versions = BTreeMap::new();
+        for (name, runtime) in [("docker", &self.docker), ("podman", &self.podman)] {
+            if let Ok(Some(version)) = runtime.version().await {
+                versions.insert(name, version);
+            }
+        }
+        versions
+    }
+
+    /// Gets the runtime that the target should be run with.
+    pub fn for_target(&self, target: &TestTarget) -> &Arc<dyn ContainerRuntime> {
+        match target.runtime {
+            RuntimeKind::Docker => &self.docker,
+            RuntimeKind::Podman => &self.podman,
+            RuntimeKind::Process => &self.process,
+            RuntimeKind::Remote => &self.remote,
+        }
+    }
+}
+
+/// A running container. It is stopped in the background if the guard is dropped before
+/// [`Container::stop`] is called, e.g. because a benchmark failed or was interrupted.
+pub struct Container {
+    pub id: String,
+    runtime: Arc<dyn ContainerRuntime>,
+    log_path: PathBuf,
+    cleanups: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    is_stopped: bool,
+}
+
+impl Container {
+    pub fn runtime(&self) -> &Arc<dyn ContainerRuntime> {
+        &self.runtime
+    }
+
+    /// Stops the container, saves its logs, then removes it.
+    pub async fn stop(mut self) -> Result<(), RuntimeError> {
+        let result = shut_down(self.runtime.as_ref(), &self.id, &self.log_path).await;
+        self.is_stopped = true;
+        result
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if self.is_stopped {
+            return;
+        }
+        let runtime = self.runtime.clone();
+        let id = self.id.clone();
+        let log_path = self.log_path.clone();
+        let cleanup = tokio::spawn(async move {
+            if let Err(e) = shut_down(runtime.as_ref(), &id, &log_path).await {
+                eprintln!("Could not stop container {}: {}", id, e);
+            }
+        });
+        self.cleanups.lock().unwrap().push(cleanup);
+    }
+}
+
+/// Stops the container, saves its logs, then removes it.
+///
+/// Returns any error from stopping it, e.g. because it ran out of memory, after cleaning it up.
+async fn shut_down(
+    runtime: &dyn ContainerRuntime,
+    id: &str,
+    log_path: &Path,
+) -> Result<(), RuntimeError> {
+    let stopped = runtime.stop_container(id).await;
+    match runtime.container_logs(id, None).await {
+        Ok(logs) => {
+            if let Err(e) = append_logs(log_path, id, &logs) {
+                eprintln!("Could not save logs of container {}: {}", id, e);
+            }
+        }
+        Err(RuntimeError::Unsupported(_)) => {}
+        Err(e) => eprintln!("Could not get logs of container {}: {}", id, e),
+    }
+    runtime.remove_container(id).await?;
+    stopped
+}
+
+fn append_logs(log_path: &Path, id: &str, logs: &str) -> io::Result<()> {
+    if let Some(dir) = log_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+    writeln!(file, "==> Container {} <==", id)?;
+    file.write_all(logs.as_bytes())
+}
+
+/// Gets the path to save a target's container logs to, under `out_dir`, removing any logs left
+/// from an earlier run.
+pub fn prep_log_path(out_dir: &Path, target: &TestTarget) -> io::Result<PathBuf> {
+    let log_path = out_dir.join(target.name()).join("container.log");
+    match fs::remove_file(&log_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(log_path),
+    }
+}
+
+/// Manages the lifecycle of the servers under test.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// Starts a container for the target, replacing any existing container with the same name.
+    ///
+    /// Returns the ID of the new container.
+    async fn start_container(&self, target: &TestTarget<'_>) -> Result<String, RuntimeError>;
+
+    /// Stops the container. Fails with [`RuntimeError::OutOfMemory`] if it had already been
+    /// killed for exceeding its memory limit.
+    async fn stop_container(&self, id: &str) -> Result<(), RuntimeError>;
+
+    async fn container_state(&self, id: &str) -> Result<ContainerState, RuntimeError>;
+
+    /// Gets a snapshot of the container's current resource usage.
+    async fn container_stats(&self, id: &str) -> Result<ContainerStats, RuntimeError>;
+
+    /// Removes a stopped container.
+    async fn remove_container(&self, _id: &str) -> Result<(), RuntimeError> {
+        Ok(())
+    }
+
+    /// Gets what the container wrote to stdout and stderr, or only the last `tail` lines if given.
+    async fn container_logs(&self, id: &str, tail: Option<usize>) -> Result<String, RuntimeError>;
+
+    /// Gets the metadata of the image the target runs, if it runs one.
+    async fn inspect_image(
+        &self,
+        _target: &TestTarget<'_>,
+    ) -> Result<Option<ImageInfo>, RuntimeError> {
+        Ok(None)
+    }
+
+    /// Builds the image the target runs from the Dockerfile in `context_dir`, returning whether
+    /// there was an image to build.
+    async fn build_image(
+        &self,
+        _target: &TestTarget<'_>,
+        _context_dir: &Path,
+    ) -> Result<bool, RuntimeError> {
+        Ok(false)
+    }
+
+    /// Gets the version of the runtime itself, if it has one.
+    async fn version(&self) -> Result<Option<String>, RuntimeError> {
+        Ok(None)
+    }
+
+    /// Removes any containers left behind by earlier runs, returning their IDs.
+    async fn remove_stale_containers(&self) -> Result<Vec<String>, RuntimeError> {
+        Ok(vec![])
+    }
+}
+
+/// The state of a container, as reported by its runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerState {
+    pub is_running: bool,
+    /// The exit code of the container's process, if it has exited.
+    pub exit_code: Option<i64>,
+    /// Whether the container was killed for exceeding its memory limit.
+    pub is_oom_killed: bool,
+}
+
+/// Identifies the image a server was run from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub id: String,
+    /// The digest the image was pulled or pushed with, if any. Locally built images have none.
+    pub digest: Option<String>,
+    pub size_bytes: u64,
+    pub num_layers: usize,
+    /// When the image was built, as an RFC 3339 timestamp.
+    pub created: String,
+}
+
+/// A snapshot of a container's resource usage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerStats {
+    /// CPU usage, as a percentage of one CPU.
+    pub cpu_percent: f64,
+    pub memory_bytes: u64,
+    /// Total bytes received by the container since it started.
+    pub net_rx_bytes: u64,
+    /// Total bytes sent by the container since it started.
+    pub net_tx_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    /// The image for a target does not exist.
+    ImageNotFound(String),
+    /// The program that starts a process target does not exist, or the target has none.
+    CommandNotFound {
+        server_name: String,
+        program: Option<String>,
+    },
+    /// An image could not be built.
+    BuildFailed {
+        image: String,
+        /// What the build printed before failing, ending with the error.
+        output: String,
+    },
+    /// The container could not bind its port, because something else is using it.
+    PortConflict(String),
+    /// The container was killed for exceeding its memory limit.
+    OutOfMemory(String),
+    ContainerNotFound(String),
+    /// The runtime responded with an unexpected error.
+    Api {
+        status: u16,
+        message: String,
+    },
+    /// The runtime could not be reached, or sent an unreadable response.
+    Connection(Box<dyn Error + Send + Sync>),
+    /// The runtime can't do this.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::ImageNotFound(image) => write!(f, "Image {} does not exist", image),
+            RuntimeError::CommandNotFound {
+                server_name,
+                program: Some(program),
+            } => write!(
+                f,
+                "Command {} of target {} does not exist",
+                program, server_name
+            ),
+            RuntimeError::CommandNotFound {
+                server_name,
+                program: None,
+            } => write!(f, "Target {} has no command to run", server_name),
+            RuntimeError::BuildFailed { image, output } => {
+                write!(f, "Building image {} failed:\n{}", image, output)
+            }
+            RuntimeError::PortConflict(message) => write!(f, "Port is already in use: {}", message),
+            RuntimeError::OutOfMemory(id) => write!(f, "Container {} ran out of memory", id),
+            RuntimeError::ContainerNotFound(id) => write!(f, "Container {} does not exist", id),
+            RuntimeError::Api { status, message } => {
+                write!(f, "Container runtime error ({}): {}", status, message)
+            }
+            RuntimeError::Connection(e) => write!(f, "Could not reach container runtime: {}", e),
+            RuntimeError::Unsupported(what) => write!(f, "Can't get the {}", what),
+        }
+    }
+}
+
+impl Error for RuntimeError {}
+
+impl From<io::Error> for RuntimeError {
+    fn from(e: io::Error) -> Self {
+        RuntimeError::Connection(e.into())
+    }
+}
+
+impl From<hyper::Error> for RuntimeError {
+    fn from(e: hyper::Error) -> Self {
+        RuntimeError::Connection(e.into())
+    }
+}
+
+impl From<serde_json::Error> for RuntimeError {
+    fn from(e: serde_json::Error) -> Self {
+        RuntimeError::Connection(e.into())
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{
+    runtime::{ContainerRuntime, ContainerStats},
+    targets::TestTarget,
+};
+
+/// How often to sample a container's resource usage. Docker takes around a second to compute each
+/// snapshot, so samples may be further apart than this.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A container's resource usage at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceSample {
+    /// When the sample was taken, relative to the start of sampling.
+    pub elapsed: Duration,
+    pub stats: ContainerStats,
+}
+
+/// The resource usage of a target's container over one benchmark run.
+#[derive(Debug)]
+pub struct ResourceUsage<'a> {
+    pub target: TestTarget<'a>,
+    /// Identifies the run within the benchmark mode, e.g. the path being benchmarked.
+    pub run: String,
+    pub samples: Vec<ResourceSample>,
+}
+
+/// Summarizes the samples in a [`ResourceUsage`].
+#[derive(Debug, PartialEq)]
+pub struct ResourceSummary {
+    pub peak_memory_bytes: u64,
+    pub mean_cpu_percent: f64,
+    /// Bytes received between the first and last samples.
+    pub net_rx_bytes: u64,
+    /// Bytes sent between the first and last samples.
+    pub net_tx_bytes: u64,
+}
+
+impl<'a> ResourceUsage<'a> {
+    pub fn summary(&self) -> Option<ResourceSummary> {
+        let first = self.samples.first()?;
+        let last = self.samples.last()?;
+        Some(ResourceSummary {
+            peak_memory_bytes: self.samples.iter().map(|s| s.stats.memory_bytes).max()?,
+            mean_cpu_percent: self
+                .samples
+                .iter()
+                .map(|s| s.stats.cpu_percent)
+                .sum::<f64>()
+                / self.samples.len() as f64,
+            net_rx_bytes: last
+                .stats
+                .net_rx_bytes
+                .saturating_sub(first.stats.net_rx_bytes),
+            net_tx_bytes: last
+                .stats
+                .net_tx_bytes
+                .saturating_sub(first.stats.net_tx_bytes),
+        })
+    }
+}
+
+/// Samples a container's resource usage in the background until stopped.
+pub struct ResourceSampler {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<Vec<ResourceSample>>,
+}
+
+impl ResourceSampler {
+    pub fn start(runtime: Arc<dyn ContainerRuntime>, container_id: &str) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+        let container_id = container_id.to_string();
+        let handle = tokio::spawn(async move {
+            let start = Instant::now();
+            let mut samples = vec![];
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = interval.tick() => {
+                        let elapsed = start.elapsed();
+                        // The container may not be up yet, or may have just exited.
+                        if let Ok(stats) = runtime.container_stats(&container_id).await {
+                            samples.push(ResourceSample { elapsed, stats });
+                        }
+                    }
+                }
+            }
+            samples
+        });
+        Self { stop, handle }
+    }
+
+    /// Stops sampling, returning all samples taken.
+    pub async fn stop<'a>(self, target: TestTarget<'a>, run: String) -> ResourceUsage<'a> {
+        let _ = self.stop.send(());
+        let samples = self.handle.await.unwrap_or_default();
+        ResourceUsage {
+            target,
+            run,
+            samples,
+        }
+    }
+}
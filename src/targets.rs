@@ -1,3 +1,71 @@
+use std::{io, net::TcpListener};
+
+use crate::{
+    config::{HealthCheck, TargetConfig},
+    cpuset::CpuSet,
+    runtime::{ImageInfo, RuntimeKind},
+};
+
+/// The content encoding that clients accept from a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "uncompressed",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "brotli",
+        }
+    }
+
+    /// The value to send in the Accept-Encoding header, if any.
+    pub fn accept_encoding(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+
+    /// Configures a client to accept (and decode) only this encoding.
+    pub fn configure_client(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match self {
+            Encoding::Identity => builder.no_gzip().no_brotli(),
+            Encoding::Gzip => builder.gzip(true).no_brotli(),
+            Encoding::Brotli => builder.no_gzip().brotli(true),
+        }
+    }
+}
+
+/// The compression settings to test, as specified on the command line or in the config.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Brotli,
+    /// Tests each of the above separately.
+    All,
+}
+
+impl Compression {
+    pub fn encodings(&self) -> Vec<Encoding> {
+        match self {
+            Compression::None => vec![Encoding::Identity],
+            Compression::Gzip => vec![Encoding::Gzip],
+            Compression::Brotli => vec![Encoding::Brotli],
+            Compression::All => vec![Encoding::Identity, Encoding::Gzip, Encoding::Brotli],
+        }
+    }
+}
+
 /// Describes a test target.
 #[derive(Debug, Clone)]
 pub struct TestTarget<'a> {
@@ -7,8 +75,28 @@ pub struct TestTarget<'a> {
     pub num_cpus: usize,
     /// The amount of RAM, in MB, the server is using for this test.
     pub ram_mb: usize,
-    /// Whether or not these tests accept compressed results.
-    pub is_compressed: bool,
+    /// The encoding these tests accept results in.
+    pub encoding: Encoding,
+    /// How the server is run.
+    pub runtime: RuntimeKind,
+    /// The command that starts the server, when it is run as a local process.
+    pub command: &'a [String],
+    /// How to tell that the server is ready.
+    pub health_check: &'a HealthCheck,
+    /// The port the server listens on inside its container.
+    pub container_port: u16,
+    /// The port on the host that the server is reachable on, or 0 if it hasn't been picked yet.
+    pub host_port: u16,
+    /// Overrides the URL requests are sent to.
+    pub base_url: Option<&'a str>,
+    /// The image the server is run from, once it has been inspected.
+    pub image: Option<&'a ImageInfo>,
+    /// The CPUs the server is pinned to, if any.
+    pub server_cpuset: Option<&'a CpuSet>,
+    /// The CPUs the load generator is pinned to, if any.
+    pub client_cpuset: Option<&'a CpuSet>,
+    /// The ID of the run that this target is benchmarked in, which every output row records.
+    pub run_id: &'a str,
 }
 
 impl<'a> TestTarget<'a> {
@@ -19,18 +107,185 @@ impl<'a> TestTarget<'a> {
 
     /// Converts this target to a unique name.
     pub fn name(&self) -> String {
-        let compression = if self.is_compressed {
-            "compressed"
-        } else {
-            "uncompressed"
-        };
+        if self.runtime == RuntimeKind::Remote {
+            return format!(
+                "{}-{}-remote",
+                self.server_name.replace("/", "-"),
+                self.encoding.as_str()
+            );
+        }
         // Docker rejects container names with "/", so convert slashes to "-".
         format!(
             "{}-{}-cpus-{}-ram-{}m",
             self.server_name.replace("/", "-"),
-            compression,
+            self.encoding.as_str(),
             self.num_cpus,
             self.ram_mb
         )
     }
+
+    /// Gets the CPUs to pin the server to: the first `num_cpus` of those available to it.
+    pub fn server_cpus(&self) -> CpuSet {
+        CpuSet::available_to_server(self.server_cpuset, self.client_cpuset).first(self.num_cpus)
+    }
+
+    /// Gets the CPUs that containers are pinned to, which they are only if either cpuset is set.
+    /// Otherwise they may run on any CPU, limited only by their CPU quota.
+    pub fn pinned_server_cpus(&self) -> Option<CpuSet> {
+        (self.server_cpuset.is_some() || self.client_cpuset.is_some()).then(|| self.server_cpus())
+    }
+
+    /// Gets the URL that requests to the server start with, without a trailing slash.
+    pub fn base_url(&self) -> String {
+        match self.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!("http://localhost:{}", self.host_port),
+        }
+    }
+
+    /// Gets the full URL for a request path.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url(), path)
+    }
+
+    /// Picks a free host port for the server, if it doesn't have one yet.
+    ///
+    /// The port is found by binding to port 0, so another process could take it before the server
+    /// starts, but this is unlikely.
+    pub fn with_host_port(&self) -> io::Result<TestTarget<'a>> {
+        let mut target = self.clone();
+        if target.host_port == 0 {
+            target.host_port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        }
+        Ok(target)
+    }
+}
+
+/// Expands each server into one target per combination of CPUs, RAM and encoding.
+///
+/// Duplicate values are ignored, so that each target has a unique name, as long as the servers'
+/// names are unique, which [`Config::validate`](crate::config::Config::validate) checks. Remote
+/// servers' resources can't be controlled, so they are only expanded by encoding, and recorded
+/// with 0 CPUs and RAM.
+pub fn expand_matrix<'a>(
+    servers: &'a [TargetConfig],
+    num_cpus: &[usize],
+    ram_mb: &[usize],
+    compression: &[Compression],
+) -> Vec<TestTarget<'a>> {
+    let num_cpus = dedup(num_cpus);
+    let ram_mb = dedup(ram_mb);
+    let encodings: Vec<Encoding> = compression.iter().flat_map(|c| c.encodings()).collect();
+    let encodings = dedup(&encodings);
+    let mut targets = vec![];
+    for server in servers {
+        let (num_cpus, ram_mb) = match server.runtime {
+            RuntimeKind::Remote => (&[0][..], &[0][..]),
+            _ => (&num_cpus[..], &ram_mb[..]),
+        };
+        for &num_cpus in num_cpus.iter() {
+            for &ram_mb in ram_mb.iter() {
+                for &encoding in encodings.iter() {
+                    targets.push(TestTarget {
+                        server_name: &server.server_name,
+                        num_cpus,
+                        ram_mb,
+                        encoding,
+                        runtime: server.runtime,
+                        command: &server.command,
+                        health_check: &server.health,
+                        container_port: server.container_port,
+                        host_port: server.host_port,
+                        base_url: server.base_url.as_deref(),
+                        image: None,
+                        server_cpuset: None,
+                        client_cpuset: None,
+                        run_id: "",
+                    });
+                }
+            }
+        }
+    }
+    targets
+}
+
+fn dedup<T: PartialEq + Copy>(values: &[T]) -> Vec<T> {
+    let mut unique = vec![];
+    for &value in values {
+        if !unique.contains(&value) {
+            unique.push(value);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_expand_matrix() {
+        let servers = ["rust-axum", "go-gin"].map(|t| TargetConfig::new(String::from(t)));
+        let targets = expand_matrix(
+            &servers,
+            &[1, 2, 1],
+            &[64, 128],
+            &[Compression::Gzip, Compression::All],
+        );
+        assert_eq!(targets.len(), 24);
+        let names: HashSet<String> = targets.iter().map(TestTarget::name).collect();
+        assert_eq!(names.len(), 24);
+        assert!(names.contains("go-gin-uncompressed-cpus-2-ram-64m"));
+        assert!(names.contains("rust-axum-brotli-cpus-1-ram-128m"));
+    }
+
+    #[test]
+    fn test_urls() {
+        let mut servers = [
+            TargetConfig::new(String::from("rust-axum")),
+            TargetConfig::new(String::from("go-gin")),
+        ];
+        servers[0].host_port = 9000;
+        servers[1].base_url = Some(String::from("http://10.0.0.2:8080/"));
+        let targets = expand_matrix(&servers, &[1], &[128], &[Compression::None]);
+        assert_eq!(
+            targets[0].url("/strings/hello"),
+            "http://localhost:9000/strings/hello"
+        );
+        assert_eq!(
+            targets[1].url("/strings/hello"),
+            "http://10.0.0.2:8080/strings/hello"
+        );
+    }
+
+    #[test]
+    fn test_picks_free_host_port() {
+        let mut servers = [TargetConfig::new(String::from("rust-axum"))];
+        servers[0].host_port = 0;
+        let targets = expand_matrix(&servers, &[1], &[128], &[Compression::None]);
+        assert_ne!(targets[0].with_host_port().unwrap().host_port, 0);
+    }
+
+    #[test]
+    fn test_pinned_server_cpus() {
+        let servers = [TargetConfig::new(String::from("rust-axum"))];
+        let mut target = expand_matrix(&servers, &[2], &[128], &[Compression::None]).remove(0);
+        assert_eq!(target.pinned_server_cpus(), None);
+        let server_cpuset: CpuSet = "2-5".parse().unwrap();
+        target.server_cpuset = Some(&server_cpuset);
+        assert_eq!(target.pinned_server_cpus().unwrap().cpus(), &[2, 3]);
+    }
+
+    #[test]
+    fn test_remote_targets_ignore_resources() {
+        let mut servers = [TargetConfig::new(String::from("rust-axum"))];
+        servers[0].runtime = RuntimeKind::Remote;
+        servers[0].base_url = Some(String::from("http://10.0.0.2:8080"));
+        let targets = expand_matrix(&servers, &[1, 2], &[128], &[Compression::All]);
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].name(), "rust-axum-uncompressed-remote");
+        assert_eq!((targets[0].num_cpus, targets[0].ram_mb), (0, 0));
+    }
 }
@@ -4,7 +4,15 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::{docker, paths::TestPath, targets::TestTarget, writes};
+use crate::{
+    config::{HealthConfig, WarmUpConfig},
+    health::{self, HealthError},
+    paths::TestPath,
+    runtime::{self, Container, RuntimeKind, Runtimes},
+    stats::ResourceSampler,
+    targets::TestTarget,
+    writes,
+};
 
 #[derive(Debug)]
 pub struct WarmUpResults<'a, 'b> {
@@ -38,23 +46,28 @@ impl<'a> WarmUpResult<'a> {
     }
 }
 
-async fn bench_path<'a: 'c, 'b, 'c>(
-    target: &'_ TestTarget<'_>,
-    path: &'a TestPath,
-    start_time: Instant,
-) -> Result<WarmUpResult<'a>, Box<dyn Error>> {
-    let mut client_builder = reqwest::Client::builder()
+/// How long to wait for each response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn client_for(target: &TestTarget) -> reqwest::Result<reqwest::Client> {
+    let client_builder = reqwest::Client::builder()
         .connect_timeout(Duration::from_millis(10))
-        .timeout(Duration::from_secs(1))
-        .gzip(target.is_compressed);
-    if !target.is_compressed {
-        client_builder = client_builder.no_brotli();
-    }
-    let client = client_builder.build()?;
-    let full_path = format!("http://localhost:8080{}", &path.path);
-    let mut result = wait_for_first_response(path, &full_path, start_time, &client).await;
+        .timeout(REQUEST_TIMEOUT);
+    target.encoding.configure_client(client_builder).build()
+}
 
-    for _ in 0..5 {
+/// Times requests to the path on a server that has just become ready. The first request is sent
+/// as soon as the server passes its health check.
+async fn bench_path<'a>(
+    client: &reqwest::Client,
+    target: &TestTarget<'_>,
+    path: &'a TestPath,
+    startup_time: Duration,
+    num_requests: usize,
+) -> Result<WarmUpResult<'a>, Box<dyn Error>> {
+    let full_path = target.url(&path.path);
+    let mut result = WarmUpResult::new(path, startup_time);
+    for _ in 0..=num_requests {
         let req_time = Instant::now();
         let _ = client.get(&full_path).send().await?;
         let duration = req_time.elapsed();
@@ -64,21 +77,40 @@ async fn bench_path<'a: 'c, 'b, 'c>(
     Ok(result)
 }
 
-async fn wait_for_first_response<'a>(
-    path: &'a TestPath,
-    full_path: &str,
+/// Polls the target's health check, returning how long after `start_time` the check that passed
+/// was sent.
+async fn wait_until_ready(
     start_time: Instant,
     client: &reqwest::Client,
-) -> WarmUpResult<'a> {
-    loop {
+    container: &Container,
+    target: &TestTarget<'_>,
+    health_config: &HealthConfig,
+) -> Result<Duration, HealthError> {
+    // Retry immediately, so that the startup time isn't inflated by waiting between attempts.
+    let config = HealthConfig {
+        poll_interval_ms: 0,
+        max_poll_interval_ms: 0,
+        ..health_config.clone()
+    };
+    let runtime = container.runtime().as_ref();
+    let probe = |time_left: Duration| async move {
         let req_time = Instant::now();
-        let resp = client.get(full_path).send().await;
-        if resp.is_ok() && resp.unwrap().status().is_success() {
-            let duration = req_time.elapsed();
-            let mut result = WarmUpResult::new(path, req_time.duration_since(start_time));
-            result.latencies.push(duration);
-            return result;
-        }
+        let is_healthy = health::is_healthy(client, target, REQUEST_TIMEOUT.min(time_left)).await;
+        is_healthy.then(|| req_time.duration_since(start_time))
+    };
+    health::poll_until_ready(runtime, &container.id, target, &config, probe).await
+}
+
+/// Checks that the startup of every target can be benchmarked. Remote servers aren't started by
+/// this tool, so they can't be.
+pub fn check_targets(targets: &[TestTarget]) -> Result<(), Box<dyn Error>> {
+    match targets.iter().find(|t| t.runtime == RuntimeKind::Remote) {
+        Some(target) => Err(format!(
+            "Can't benchmark warm-up of remote target {}, since it can't be restarted",
+            target.name()
+        )
+        .into()),
+        None => Ok(()),
     }
 }
 
@@ -86,34 +118,58 @@ async fn wait_for_first_response<'a>(
 pub async fn benchmark_all<'a>(
     targets: &Vec<TestTarget<'a>>,
     out_dir: PathBuf,
+    config: &'a WarmUpConfig,
+    health_config: &HealthConfig,
+    runtimes: &Runtimes,
 ) -> Result<(), Box<dyn Error>> {
     let mut requests_csv_path = out_dir.clone();
     requests_csv_path.push("request-benchmarks.csv");
     let mut requests_csv = csv::Writer::from_path(&requests_csv_path)?;
-    let mut start_times_csv_path = out_dir;
+    let mut start_times_csv_path = out_dir.clone();
     start_times_csv_path.push("start-time-benchmarks.csv");
     let mut start_times_csv = csv::Writer::from_path(&start_times_csv_path)?;
-
-    lazy_static! {
-        static ref TEST_PATHS: [TestPath; 4] = [
-            TestPath::new("/strings/hello", "hello"),
-            TestPath::new("/strings/lines?n=50000", "lines"),
-            TestPath::new("/static/basic.html", "static-text"),
-            TestPath::new("/math/power-reciprocals-alt?n=1000000", "powers-sum")
-        ];
-    }
+    let mut samples_csv_path = out_dir.clone();
+    samples_csv_path.push("resource-samples.csv");
+    let mut samples_csv = csv::Writer::from_path(&samples_csv_path)?;
+    let mut summary_csv_path = out_dir.clone();
+    summary_csv_path.push("resource-summary.csv");
+    let mut summary_csv = csv::Writer::from_path(&summary_csv_path)?;
 
     for target in targets {
+        let target = &target.with_host_port()?;
+        let log_path = runtime::prep_log_path(&out_dir, target)?;
         println!("Benchmarking warm up for {:?}", target);
         let mut results = WarmUpResults::new(target.clone());
-        for path in TEST_PATHS.iter() {
-            for i in 0..3 {
-                let name = docker::start_container(&target)?;
+        'paths: for path in config.paths.iter() {
+            for i in 0..config.iterations {
+                let container =
+                    match health::start(runtimes, target, &log_path, health_config).await? {
+                        Some(container) => container,
+                        None => break 'paths,
+                    };
+                let start_time = Instant::now();
+                let sampler = ResourceSampler::start(container.runtime().clone(), &container.id);
 
-                let result = bench_path(target, path, Instant::now()).await?;
+                let client = client_for(target)?;
+                let ready =
+                    wait_until_ready(start_time, &client, &container, target, health_config).await;
+                let startup_time = match ready {
+                    Ok(startup_time) => startup_time,
+                    Err(e) => {
+                        health::handle_unhealthy(container, health_config, e).await?;
+                        // Keep the results of the iterations that did become healthy.
+                        break 'paths;
+                    }
+                };
+                let result =
+                    bench_path(&client, target, path, startup_time, config.requests).await?;
                 results.per_path.push(result);
 
-                docker::kill_container(&name)?;
+                let usage = sampler
+                    .stop(target.clone(), format!("{}-{}", path.name, i))
+                    .await;
+                writes::write_resource_usage(&mut samples_csv, &mut summary_csv, &usage)?;
+                container.stop().await?;
                 let last_result = results.per_path.last().unwrap();
                 println!(
                     "Benchmarked warm-up {} on path {:?}.\n\tStartup: {:?}\n\tLatencies: {:?}",
@@ -1,9 +1,16 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::Write;
 use std::time::Duration;
 
-use crate::metrics::{Metric, MetricData};
+use crate::capacity::CapacityResult;
+use crate::metrics::{Metric, MetricData, Percentile};
+use crate::noise::NoiseReport;
+use crate::paths::TestPath;
 use crate::perf::PerfResult;
+use crate::runtime::ImageInfo;
+use crate::stats::ResourceUsage;
+use crate::targets::TestTarget;
 use crate::warm_up::WarmUpResults;
 
 #[derive(serde::Serialize)]
@@ -23,66 +30,222 @@ impl From<&MetricData<Duration>> for LatencyRow {
     }
 }
 
+/// The columns that each perf, warm-up and capacity result starts with, which identify the target
+/// and path it is for, and what the target ran on.
+///
+/// csv can't write `#[serde(flatten)]` fields, so results are written as a tuple of this and a row
+/// of their own columns.
 #[derive(serde::Serialize)]
-struct PerfResultRow<'a> {
+struct TargetPathRow<'a> {
+    run_id: &'a str,
     name: &'a str,
     path: &'a str,
     server_name: &'a str,
     num_cpus: usize,
     ram_mb: usize,
+    encoding: &'static str,
     target: String,
-    latency_mean_ms: f64,
-    latency_std_dev_ms: f64,
-    latency_max_ms: f64,
-    qps_mean: f64,
-    qps_std_dev: f64,
-    qps_max: f64,
-}
-
-impl<'a: 'c, 'b: 'c, 'c> From<PerfResult<'a, 'b>> for PerfResultRow<'c> {
-    fn from(result: PerfResult<'a, 'b>) -> Self {
-        let latency_metric = result
-            .metrics
+    image_id: Option<&'a str>,
+    image_digest: Option<&'a str>,
+    image_size_bytes: Option<u64>,
+    image_layers: Option<usize>,
+    image_created: Option<&'a str>,
+    /// The CPUs the server was pinned to, rather than the whole server cpuset.
+    server_cpuset: Option<String>,
+    client_cpuset: Option<String>,
+}
+
+impl<'a> TargetPathRow<'a> {
+    fn new(target: &'a TestTarget, path: &'a TestPath) -> Self {
+        TargetPathRow {
+            run_id: target.run_id,
+            name: &path.name,
+            path: &path.path,
+            server_name: target.server_name,
+            num_cpus: target.num_cpus,
+            ram_mb: target.ram_mb,
+            encoding: target.encoding.as_str(),
+            target: target.name(),
+            image_id: target.image.map(|i| i.id.as_str()),
+            image_digest: target.image.and_then(|i| i.digest.as_deref()),
+            image_size_bytes: target.image.map(|i| i.size_bytes),
+            image_layers: target.image.map(|i| i.num_layers),
+            image_created: target.image.map(|i| i.created.as_str()),
+            server_cpuset: target.pinned_server_cpus().map(|c| c.to_string()),
+            client_cpuset: target.client_cpuset.map(|c| c.to_string()),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct PerfResultRow {
+    target_qps: Option<f64>,
+    threads: Option<usize>,
+    connections: Option<usize>,
+    latency_mean_ms: Option<f64>,
+    latency_std_dev_ms: Option<f64>,
+    latency_max_ms: Option<f64>,
+    latency_p50_ms: Option<f64>,
+    latency_p75_ms: Option<f64>,
+    latency_p90_ms: Option<f64>,
+    latency_p99_ms: Option<f64>,
+    /// wrk only reports percentiles up to p99, so this is only filled by the native engine and
+    /// wrk2.
+    latency_p99_9_ms: Option<f64>,
+    /// Only counts 2xx and 3xx responses, whichever engine measured it.
+    requests_per_sec: Option<f64>,
+    thread_qps_mean: Option<f64>,
+    thread_qps_std_dev: Option<f64>,
+    thread_qps_max: Option<f64>,
+    requests: Option<u64>,
+    duration_secs: Option<f64>,
+    bytes_read: Option<f64>,
+    transfer_bytes_per_sec: Option<f64>,
+    connect_errors: Option<u64>,
+    read_errors: Option<u64>,
+    write_errors: Option<u64>,
+    timeout_errors: Option<u64>,
+    non_2xx_3xx_responses: Option<u64>,
+    is_noisy: bool,
+    error: Option<String>,
+}
+
+/// Finds the latency, in ms, recorded for the given percentile, if any.
+fn find_percentile_ms(metrics: &[Metric], percentile: f64) -> Option<f64> {
+    metrics.iter().find_map(|m| match m {
+        Metric::LatencyPercentile(Percentile {
+            percentile: p,
+            latency,
+        }) if (p - percentile).abs() < 1e-9 => Some(latency.as_secs_f64() * 1000.0),
+        _ => None,
+    })
+}
+
+impl From<&PerfResult<'_, '_>> for PerfResultRow {
+    fn from(result: &PerfResult) -> Self {
+        let latency_row = result.latency().map(LatencyRow::from);
+        let thread_qps = result.thread_qps();
+        let totals = result.totals();
+        let socket_errors = result.socket_errors();
+        PerfResultRow {
+            target_qps: result.target_rate,
+            threads: result.threads,
+            connections: result.connections,
+            latency_mean_ms: latency_row.as_ref().map(|l| l.mean_ms),
+            latency_std_dev_ms: latency_row.as_ref().map(|l| l.std_dev_ms),
+            latency_max_ms: latency_row.as_ref().map(|l| l.max_ms),
+            latency_p50_ms: find_percentile_ms(&result.metrics, 50.0),
+            latency_p75_ms: find_percentile_ms(&result.metrics, 75.0),
+            latency_p90_ms: find_percentile_ms(&result.metrics, 90.0),
+            latency_p99_ms: find_percentile_ms(&result.metrics, 99.0),
+            latency_p99_9_ms: find_percentile_ms(&result.metrics, 99.9),
+            requests_per_sec: result.requests_per_sec(),
+            thread_qps_mean: thread_qps.map(|q| q.mean),
+            thread_qps_std_dev: thread_qps.map(|q| q.std_dev),
+            thread_qps_max: thread_qps.map(|q| q.max),
+            requests: totals.map(|t| t.requests),
+            duration_secs: totals.map(|t| t.duration.as_secs_f64()),
+            bytes_read: totals.map(|t| t.bytes_read),
+            transfer_bytes_per_sec: result.transfer_per_sec(),
+            connect_errors: socket_errors.map(|e| e.connect),
+            read_errors: socket_errors.map(|e| e.read),
+            write_errors: socket_errors.map(|e| e.write),
+            timeout_errors: socket_errors.map(|e| e.timeout),
+            non_2xx_3xx_responses: result.non_2xx_3xx_responses(),
+            is_noisy: result.is_noisy,
+            error: result.error.clone(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct PerfRequestRow<'a> {
+    run_id: &'a str,
+    name: &'a str,
+    path: &'a str,
+    server_name: &'a str,
+    num_cpus: usize,
+    ram_mb: usize,
+    encoding: &'static str,
+    target: String,
+    target_qps: Option<f64>,
+    sent_at_ms: f64,
+    latency_ms: f64,
+    is_success: bool,
+}
+
+impl<'a: 'c, 'b: 'c, 'c> From<&PerfResult<'a, 'b>> for Vec<PerfRequestRow<'c>> {
+    fn from(result: &PerfResult<'a, 'b>) -> Self {
+        result
+            .timings
             .iter()
-            .find(|m| matches!(m, Metric::Latency(_)))
-            .unwrap();
-        let latency_row: LatencyRow = match latency_metric {
-            Metric::Latency(data) => data.into(),
-            _ => panic!("Impossible"),
+            .map(|timing| PerfRequestRow {
+                run_id: result.target.run_id,
+                name: &result.path.name,
+                path: &result.path.path,
+                server_name: result.target.server_name,
+                num_cpus: result.target.num_cpus,
+                ram_mb: result.target.ram_mb,
+                encoding: result.target.encoding.as_str(),
+                target: result.target.name(),
+                target_qps: result.target_rate,
+                sent_at_ms: timing.sent_at.as_secs_f64() * 1000.0,
+                latency_ms: timing.latency.as_secs_f64() * 1000.0,
+                is_success: timing.is_success(),
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct LatencyDistributionRow<'a> {
+    run_id: &'a str,
+    name: &'a str,
+    path: &'a str,
+    server_name: &'a str,
+    num_cpus: usize,
+    ram_mb: usize,
+    encoding: &'static str,
+    target: String,
+    target_qps: Option<f64>,
+    percentile: f64,
+    latency_ms: f64,
+}
+
+impl<'a: 'c, 'b: 'c, 'c> From<&PerfResult<'a, 'b>> for Vec<LatencyDistributionRow<'c>> {
+    fn from(result: &PerfResult<'a, 'b>) -> Self {
+        let Some(distribution) = result.latency_distribution() else {
+            return vec![];
         };
-        let qps_metric = result
-            .metrics
+        distribution
+            .percentiles
             .iter()
-            .find(|m| matches!(m, Metric::Qps(_)))
-            .unwrap();
-        let qps_data: MetricData<f64> = match qps_metric {
-            Metric::Qps(data) => data.clone(),
-            _ => panic!("Impossible"),
-        };
-        PerfResultRow {
-            name: &result.path.name,
-            path: &result.path.path,
-            server_name: result.target.server_name,
-            num_cpus: result.target.num_cpus,
-            ram_mb: result.target.ram_mb,
-            target: result.target.name(),
-            latency_mean_ms: latency_row.mean_ms,
-            latency_std_dev_ms: latency_row.std_dev_ms,
-            latency_max_ms: latency_row.max_ms,
-            qps_mean: qps_data.mean,
-            qps_std_dev: qps_data.std_dev,
-            qps_max: qps_data.max,
-        }
+            .map(|p| LatencyDistributionRow {
+                run_id: result.target.run_id,
+                name: &result.path.name,
+                path: &result.path.path,
+                server_name: result.target.server_name,
+                num_cpus: result.target.num_cpus,
+                ram_mb: result.target.ram_mb,
+                encoding: result.target.encoding.as_str(),
+                target: result.target.name(),
+                target_qps: result.target_rate,
+                percentile: p.percentile,
+                latency_ms: p.latency.as_secs_f64() * 1000.0,
+            })
+            .collect()
     }
 }
 
 #[derive(serde::Serialize)]
 struct WarmUpRequestRow<'a> {
+    run_id: &'a str,
     name: &'a str,
     path: &'a str,
     server_name: &'a str,
     num_cpus: usize,
     ram_mb: usize,
+    encoding: &'static str,
     target: String,
     request_number: usize,
     latency_ms: f64,
@@ -99,11 +262,13 @@ impl<'a: 'c, 'b: 'c, 'c> From<&WarmUpResults<'a, 'b>> for Vec<WarmUpRequestRow<'
                     .iter()
                     .enumerate()
                     .map(|(i, duration)| WarmUpRequestRow {
+                        run_id: result.target.run_id,
                         name: &path_result.path.name,
                         path: &path_result.path.path,
                         server_name: result.target.server_name,
                         num_cpus: result.target.num_cpus,
                         ram_mb: result.target.ram_mb,
+                        encoding: result.target.encoding.as_str(),
                         target: result.target.name(),
                         request_number: i + 1,
                         latency_ms: duration.as_secs_f64() * 1000.0,
@@ -114,39 +279,280 @@ impl<'a: 'c, 'b: 'c, 'c> From<&WarmUpResults<'a, 'b>> for Vec<WarmUpRequestRow<'
 }
 
 #[derive(serde::Serialize)]
-struct ServerStartRow<'a> {
-    name: &'a str,
-    path: &'a str,
+struct ServerStartRow {
+    start_up_latency_ms: f64,
+}
+
+impl<'c> From<&'c WarmUpResults<'_, '_>> for Vec<(TargetPathRow<'c>, ServerStartRow)> {
+    fn from(result: &'c WarmUpResults) -> Self {
+        result
+            .per_path
+            .iter()
+            .map(|path_result| {
+                (
+                    TargetPathRow::new(&result.target, path_result.path),
+                    ServerStartRow {
+                        start_up_latency_ms: path_result.startup_time.as_secs_f64() * 1000.0,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CapacityResultRow {
+    slo_p99_ms: f64,
+    slo_max_error_rate: f64,
+    max_rate: Option<f64>,
+    max_qps: Option<f64>,
+    p99_ms: Option<f64>,
+    error_rate: Option<f64>,
+    num_probes: usize,
+}
+
+impl From<&CapacityResult<'_, '_>> for CapacityResultRow {
+    fn from(result: &CapacityResult) -> Self {
+        let probe = result.max_sustainable.as_ref();
+        CapacityResultRow {
+            slo_p99_ms: result.slo.p99_latency.as_secs_f64() * 1000.0,
+            slo_max_error_rate: result.slo.max_error_rate,
+            max_rate: probe.map(|p| p.rate),
+            max_qps: probe.map(|p| p.achieved_qps),
+            p99_ms: probe.map(|p| p.p99_latency.as_secs_f64() * 1000.0),
+            error_rate: probe.map(|p| p.error_rate),
+            num_probes: result.num_probes,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ResourceSampleRow<'a> {
+    run_id: &'a str,
+    run: &'a str,
     server_name: &'a str,
     num_cpus: usize,
     ram_mb: usize,
+    encoding: &'static str,
     target: String,
-    start_up_latency_ms: f64,
+    elapsed_ms: f64,
+    cpu_percent: f64,
+    memory_bytes: u64,
+    net_rx_bytes: u64,
+    net_tx_bytes: u64,
 }
 
-impl<'a: 'c, 'b: 'c, 'c> From<&WarmUpResults<'a, 'b>> for Vec<ServerStartRow<'c>> {
-    fn from(result: &WarmUpResults<'a, 'b>) -> Self {
-        result
-            .per_path
+impl<'a: 'c, 'c> From<&'c ResourceUsage<'a>> for Vec<ResourceSampleRow<'c>> {
+    fn from(usage: &'c ResourceUsage<'a>) -> Self {
+        usage
+            .samples
             .iter()
-            .map(|path_result| ServerStartRow {
-                name: &path_result.path.name,
-                path: &path_result.path.path,
-                server_name: result.target.server_name,
-                num_cpus: result.target.num_cpus,
-                ram_mb: result.target.ram_mb,
-                target: result.target.name(),
-                start_up_latency_ms: path_result.startup_time.as_secs_f64() * 1000.0,
+            .map(|sample| ResourceSampleRow {
+                run_id: usage.target.run_id,
+                run: &usage.run,
+                server_name: usage.target.server_name,
+                num_cpus: usage.target.num_cpus,
+                ram_mb: usage.target.ram_mb,
+                encoding: usage.target.encoding.as_str(),
+                target: usage.target.name(),
+                elapsed_ms: sample.elapsed.as_secs_f64() * 1000.0,
+                cpu_percent: sample.stats.cpu_percent,
+                memory_bytes: sample.stats.memory_bytes,
+                net_rx_bytes: sample.stats.net_rx_bytes,
+                net_tx_bytes: sample.stats.net_tx_bytes,
             })
             .collect()
     }
 }
 
+#[derive(serde::Serialize)]
+struct ResourceSummaryRow<'a> {
+    run_id: &'a str,
+    run: &'a str,
+    server_name: &'a str,
+    num_cpus: usize,
+    ram_mb: usize,
+    encoding: &'static str,
+    target: String,
+    num_samples: usize,
+    peak_memory_bytes: Option<u64>,
+    mean_cpu_percent: Option<f64>,
+    net_rx_bytes: Option<u64>,
+    net_tx_bytes: Option<u64>,
+}
+
+impl<'a: 'c, 'c> From<&'c ResourceUsage<'a>> for ResourceSummaryRow<'c> {
+    fn from(usage: &'c ResourceUsage<'a>) -> Self {
+        let summary = usage.summary();
+        ResourceSummaryRow {
+            run_id: usage.target.run_id,
+            run: &usage.run,
+            server_name: usage.target.server_name,
+            num_cpus: usage.target.num_cpus,
+            ram_mb: usage.target.ram_mb,
+            encoding: usage.target.encoding.as_str(),
+            target: usage.target.name(),
+            num_samples: usage.samples.len(),
+            peak_memory_bytes: summary.as_ref().map(|s| s.peak_memory_bytes),
+            mean_cpu_percent: summary.as_ref().map(|s| s.mean_cpu_percent),
+            net_rx_bytes: summary.as_ref().map(|s| s.net_rx_bytes),
+            net_tx_bytes: summary.as_ref().map(|s| s.net_tx_bytes),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct NoiseRow<'a> {
+    run_id: &'a str,
+    run: &'a str,
+    server_name: &'a str,
+    num_cpus: usize,
+    ram_mb: usize,
+    encoding: &'static str,
+    target: String,
+    baseline_cpu_percent: Option<f64>,
+    baseline_steal_percent: Option<f64>,
+    max_steal_percent: Option<f64>,
+    max_background_cpu_percent: Option<f64>,
+    max_load_avg: Option<f64>,
+    min_cpu_mhz: Option<f64>,
+    max_cpu_mhz: Option<f64>,
+    cpu_mhz_drop_percent: Option<f64>,
+    num_samples: usize,
+    is_noisy: bool,
+    noise_reasons: String,
+}
+
+impl<'a: 'c, 'c> From<&'c NoiseReport<'a>> for NoiseRow<'c> {
+    fn from(report: &'c NoiseReport<'a>) -> Self {
+        NoiseRow {
+            run_id: report.target.run_id,
+            run: &report.run,
+            server_name: report.target.server_name,
+            num_cpus: report.target.num_cpus,
+            ram_mb: report.target.ram_mb,
+            encoding: report.target.encoding.as_str(),
+            target: report.target.name(),
+            baseline_cpu_percent: report.baseline.map(|b| b.busy_percent),
+            baseline_steal_percent: report.baseline.map(|b| b.steal_percent),
+            max_steal_percent: report.max_steal_percent(),
+            max_background_cpu_percent: report.max_background_cpu_percent(),
+            max_load_avg: report.max_load_avg(),
+            min_cpu_mhz: report.min_cpu_mhz(),
+            max_cpu_mhz: report.max_cpu_mhz(),
+            cpu_mhz_drop_percent: report.cpu_mhz_drop_percent(),
+            num_samples: report.samples.len(),
+            is_noisy: report.is_noisy(),
+            noise_reasons: report.reasons.join("; "),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ImageRow<'a> {
+    run_id: &'a str,
+    server_name: &'a str,
+    image: String,
+    image_id: &'a str,
+    image_digest: Option<&'a str>,
+    image_size_bytes: u64,
+    image_layers: usize,
+    image_created: &'a str,
+}
+
+impl<'a> ImageRow<'a> {
+    fn new(target: &TestTarget<'a>, image: &'a ImageInfo) -> Self {
+        ImageRow {
+            run_id: target.run_id,
+            server_name: target.server_name,
+            image: target.docker_target(),
+            image_id: &image.id,
+            image_digest: image.digest.as_deref(),
+            image_size_bytes: image.size_bytes,
+            image_layers: image.num_layers,
+            image_created: &image.created,
+        }
+    }
+}
+
+/// Writes the image of each server that is run from one.
+pub fn write_images<W: Write>(
+    writer: &mut csv::Writer<W>,
+    targets: &[TestTarget],
+) -> Result<(), Box<dyn Error>> {
+    let mut server_names = vec![];
+    for target in targets {
+        let Some(image) = target.image else {
+            continue;
+        };
+        if !server_names.contains(&target.server_name) {
+            server_names.push(target.server_name);
+            writer.serialize(ImageRow::new(target, image))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct BuildRow<'a> {
+    run_id: &'a str,
+    server_name: &'a str,
+    image: String,
+    build_duration_ms: f64,
+    image_size_bytes: Option<u64>,
+}
+
+/// Writes how long each built image took to build, and its size.
+pub fn write_builds<W: Write>(
+    writer: &mut csv::Writer<W>,
+    targets: &[TestTarget],
+    build_times: &HashMap<String, Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let mut server_names = vec![];
+    for target in targets {
+        let Some(build_time) = build_times.get(target.server_name) else {
+            continue;
+        };
+        if !server_names.contains(&target.server_name) {
+            server_names.push(target.server_name);
+            writer.serialize(BuildRow {
+                run_id: target.run_id,
+                server_name: target.server_name,
+                image: target.docker_target(),
+                build_duration_ms: build_time.as_secs_f64() * 1000.0,
+                image_size_bytes: target.image.map(|i| i.size_bytes),
+            })?;
+        }
+    }
+    Ok(())
+}
+
 pub fn write_perf_result<W: Write>(
     writer: &mut csv::Writer<W>,
     result: PerfResult,
 ) -> Result<(), Box<dyn Error>> {
-    writer.serialize(PerfResultRow::from(result))?;
+    writer.serialize((
+        TargetPathRow::new(&result.target, result.path),
+        PerfResultRow::from(&result),
+    ))?;
+    Ok(())
+}
+
+pub fn write_perf_request_timings<W: Write>(
+    writer: &mut csv::Writer<W>,
+    result: &PerfResult,
+) -> Result<(), Box<dyn Error>> {
+    let rows: Vec<PerfRequestRow> = result.into();
+    rows.iter().try_for_each(|row| writer.serialize(row))?;
+    Ok(())
+}
+
+pub fn write_latency_distribution<W: Write>(
+    writer: &mut csv::Writer<W>,
+    result: &PerfResult,
+) -> Result<(), Box<dyn Error>> {
+    let rows: Vec<LatencyDistributionRow> = result.into();
+    rows.iter().try_for_each(|row| writer.serialize(row))?;
     Ok(())
 }
 
@@ -155,9 +561,7 @@ pub fn write_warm_up_request_results<W: Write>(
     results: &WarmUpResults,
 ) -> Result<(), Box<dyn Error>> {
     let rows: Vec<WarmUpRequestRow> = results.into();
-    rows.iter()
-        .map(|row| writer.serialize(row))
-        .collect::<Result<_, csv::Error>>()?;
+    rows.iter().try_for_each(|row| writer.serialize(row))?;
     Ok(())
 }
 
@@ -165,9 +569,39 @@ pub fn write_warm_up_start_time_results<W: Write>(
     writer: &mut csv::Writer<W>,
     results: &WarmUpResults,
 ) -> Result<(), Box<dyn Error>> {
-    let rows: Vec<ServerStartRow> = results.into();
+    let rows: Vec<(TargetPathRow, ServerStartRow)> = results.into();
+    rows.iter().try_for_each(|row| writer.serialize(row))?;
+    Ok(())
+}
+
+pub fn write_capacity_result<W: Write>(
+    writer: &mut csv::Writer<W>,
+    result: &CapacityResult,
+) -> Result<(), Box<dyn Error>> {
+    writer.serialize((
+        TargetPathRow::new(&result.target, result.path),
+        CapacityResultRow::from(result),
+    ))?;
+    Ok(())
+}
+
+pub fn write_noise<W: Write>(
+    writer: &mut csv::Writer<W>,
+    report: &NoiseReport,
+) -> Result<(), Box<dyn Error>> {
+    writer.serialize(NoiseRow::from(report))?;
+    Ok(())
+}
+
+/// Writes each resource usage sample to `samples_writer`, and a summary to `summary_writer`.
+pub fn write_resource_usage<W: Write>(
+    samples_writer: &mut csv::Writer<W>,
+    summary_writer: &mut csv::Writer<W>,
+    usage: &ResourceUsage,
+) -> Result<(), Box<dyn Error>> {
+    let rows: Vec<ResourceSampleRow> = usage.into();
     rows.iter()
-        .map(|row| writer.serialize(row))
-        .collect::<Result<_, csv::Error>>()?;
+        .try_for_each(|row| samples_writer.serialize(row))?;
+    summary_writer.serialize(ResourceSummaryRow::from(usage))?;
     Ok(())
 }